@@ -7,24 +7,61 @@ enum class FFIError: uint32_t {
 	QueueLengthNotPowerTwo = 2,
 	QueueUnInitialized = 3,
 	QueueEmpty = 4,
-	SpedPast = 5 
+	SpedPast = 5,
+	QueueFull = 6,
+	MessageTooLarge = 7,
+	BufferTooSmall = 8,
+	InvalidCursorName = 9,
+	CursorTableFull = 10,
+	FingerprintMismatch = 11,
+	SharedMemoryTooSmall = 12,
+	Timeout = 13,
+	SharedMemoryError = 14,
+	InvalidPath = 15
 };
 
 enum class QueueType: uint8_t {
 	Unknown,
 	MPMC,
-	SPMC
+	SPMC,
+	SPSC,
+	MPSC
 };
 
 
-struct QueueHeader {
+struct Fingerprint {
+    uint32_t magic;
+    uint32_t layout_version;
+    uint32_t size;
+    uint32_t align;
+    uint64_t type_hash;
+};
+
+struct alignas(64) Cursor {
+    std::atomic<std::uint32_t> state;
+    uint8_t _pad[4];
+    std::atomic<std::size_t> count;
+    std::atomic<std::size_t> sped_past;
+    char name[40];
+};
+
+struct alignas(64) QueueHeader {
     QueueType queue_type;
-    uint8_t elsize_shift_left_bits;
     uint8_t is_initialized;
-    uint8_t _pad;
-    uint32_t elsize;
+    std::atomic<bool> removed;
+    uint8_t clock;
+    uint8_t _pad1[4];
+    std::size_t elsize;
     std::size_t mask;
     std::atomic<std::size_t> count;
+    Fingerprint fingerprint;
+    std::atomic<uint32_t> waiters;
+    std::atomic<uint32_t> futex_seq;
+    std::atomic<std::size_t> read_count;
+    std::atomic<uint64_t> heartbeat_nanos;
+    std::atomic<uint32_t> heartbeat_pid;
+    uint8_t _pad3[44];
+    Cursor cursors[8];
 };
 
 
@@ -33,15 +70,23 @@ struct Consumer {
                 std::size_t  mask;
                 std::size_t  expected_version;
                 std::uint8_t is_running;
-                std::uint8_t _pad[7];
+                std::uint8_t lossless;
+                std::uint8_t _pad[6];
                 QueueHeader* queue;
                 std::size_t  queue_size_in_bytes;
+                Cursor*      cursor;
+                std::uint8_t _pad2[8];
+                std::size_t  skipped;
+                std::size_t  sped_past;
+                std::uint8_t recovery_policy;
+                void*        recovery_callback;
 };
 
 struct Producer {
     alignas(64) std::uint8_t  produced_first;
                 QueueHeader* queue;
                 std::size_t  queue_size_in_bytes;
+                std::size_t  read_count;
 };
 
 extern "C" {
@@ -59,4 +104,12 @@ extern "C" {
 	FFIError produce_120(Producer* producer, void* msg);
 	FFIError consume_56(Consumer* consumer, void* msg);
 	FFIError produce_56(Producer* producer, void* msg);
+
+	// Variable length messages, queue_len is in 64 byte slots
+	FFIError byte_queue_size_in_bytes(size_t queue_len, size_t* size_in_bytes);
+	FFIError init_byte_queue(uint8_t* ptr, QueueType queue_type, size_t queue_len);
+	FFIError init_byte_producer(QueueHeader* queue, Producer* producer);
+	FFIError init_byte_consumer(const char* path, Consumer* consumer);
+	FFIError produce_bytes(Producer* producer, const uint8_t* msg, size_t len);
+	FFIError consume_bytes(Consumer* consumer, uint8_t* dest, size_t capacity, size_t* len);
 }
@@ -2,7 +2,7 @@ use ma_queues::queue::QueueType;
 use ma_queues::vector::SeqlockVector;
 use ma_queues::{
     queue::{Consumer, Producer, Queue, QueueHeader},
-    QueueError, ReadError,
+    ByteConsumer, ByteProducer, ByteQueue, ByteReadError, QueueError, ReadError, Timeout, WriteError,
 };
 use thiserror::Error;
 
@@ -23,6 +23,26 @@ pub enum FFIError {
     QueueEmpty,
     #[error("ReadError: Got sped past")]
     SpedPast,
+    #[error("WriteError: Queue is full")]
+    QueueFull,
+    #[error("WriteError: Message larger than the queue")]
+    MessageTooLarge,
+    #[error("ReadError: Destination buffer too small")]
+    BufferTooSmall,
+    #[error("Cursor name empty or too long")]
+    InvalidCursorName,
+    #[error("No free cursor left in the queue header")]
+    CursorTableFull,
+    #[error("Queue was created with a different layout or message size")]
+    FingerprintMismatch,
+    #[error("Existing shared memory too small")]
+    SharedMemoryTooSmall,
+    #[error("ReadError: Timed out")]
+    Timeout,
+    #[error("Unable to create or open the shared memory")]
+    SharedMemoryError,
+    #[error("Path is not valid UTF-8")]
+    InvalidPath,
 }
 
 impl From<ReadError> for FFIError {
@@ -33,6 +53,21 @@ impl From<ReadError> for FFIError {
         }
     }
 }
+
+impl From<Timeout> for FFIError {
+    fn from(_: Timeout) -> Self {
+        Self::Timeout
+    }
+}
+
+impl From<WriteError> for FFIError {
+    fn from(value: WriteError) -> Self {
+        match value {
+            WriteError::Full => Self::QueueFull,
+            WriteError::TooLarge => Self::MessageTooLarge,
+        }
+    }
+}
 impl From<()> for FFIError {
     fn from(value: ()) -> Self {
         Self::Success
@@ -45,7 +80,11 @@ impl From<QueueError> for FFIError {
             QueueError::UnInitialized => Self::QueueUnInitialized,
             QueueError::LengthNotPowerOfTwo => Self::QueueLengthNotPowerTwo,
             QueueError::ElementSizeNotPowerTwo => Self::UnsupportedMessageSize,
-            QueueError::SharedMemoryError(_) => todo!(),
+            QueueError::InvalidCursorName => Self::InvalidCursorName,
+            QueueError::CursorTableFull => Self::CursorTableFull,
+            QueueError::FingerprintMismatch { .. } => Self::FingerprintMismatch,
+            QueueError::TooSmall => Self::SharedMemoryTooSmall,
+            QueueError::SharedMemoryError(_) => Self::SharedMemoryError,
         }
     }
 }
@@ -88,31 +127,31 @@ pub extern "C" fn SeqlockVectorSizeInBytes(
 pub extern "C" fn seqlockvector_size_in_bytes(ptr: *mut u8, msgsize_bytes: u32, len: usize) -> FFIError {
     match msgsize_bytes {
         56 => {
-            SeqlockVector::<[u8; 56]>::from_uninitialized_ptr(ptr, len);
+            unsafe { SeqlockVector::<[u8; 56]>::from_uninitialized_ptr(ptr, len) };
         }
         120 => {
-            SeqlockVector::<[u8; 120]>::from_uninitialized_ptr(ptr, len);
+            unsafe { SeqlockVector::<[u8; 120]>::from_uninitialized_ptr(ptr, len) };
         }
         248 => {
-            SeqlockVector::<[u8; 248]>::from_uninitialized_ptr(ptr, len);
+            unsafe { SeqlockVector::<[u8; 248]>::from_uninitialized_ptr(ptr, len) };
         }
         504 => {
-            SeqlockVector::<[u8; 504]>::from_uninitialized_ptr(ptr, len);
+            unsafe { SeqlockVector::<[u8; 504]>::from_uninitialized_ptr(ptr, len) };
         }
         1016 => {
-            SeqlockVector::<[u8; 1016]>::from_uninitialized_ptr(ptr, len);
+            unsafe { SeqlockVector::<[u8; 1016]>::from_uninitialized_ptr(ptr, len) };
         }
         1656 => {
-            SeqlockVector::<[u8; 1656]>::from_uninitialized_ptr(ptr, len);
+            unsafe { SeqlockVector::<[u8; 1656]>::from_uninitialized_ptr(ptr, len) };
         }
         2040 => {
-            SeqlockVector::<[u8; 2040]>::from_uninitialized_ptr(ptr, len);
+            unsafe { SeqlockVector::<[u8; 2040]>::from_uninitialized_ptr(ptr, len) };
         }
         4088 => {
-            SeqlockVector::<[u8; 4088]>::from_uninitialized_ptr(ptr, len);
+            unsafe { SeqlockVector::<[u8; 4088]>::from_uninitialized_ptr(ptr, len) };
         }
         7224 => {
-            SeqlockVector::<[u8; 7224]>::from_uninitialized_ptr(ptr, len);
+            unsafe { SeqlockVector::<[u8; 7224]>::from_uninitialized_ptr(ptr, len) };
         }
         _ => return FFIError::UnsupportedMessageSize,
     }
@@ -144,16 +183,93 @@ pub extern "C" fn queue_size_in_bytes(msgsize_bytes: u32, len: usize, queuesize_
 #[inline(always)]
 pub extern "C" fn InitQueue(ptr: *mut u8, queue_type: QueueType, msgsize_bytes: u32, len: usize) -> FFIError {
     match msgsize_bytes {
-        56 => Queue::<[u8; 56]>::from_uninitialized_ptr(ptr, len, queue_type).into(),
-        120 => Queue::<[u8; 120]>::from_uninitialized_ptr(ptr, len, queue_type).into(),
-        248 => Queue::<[u8; 248]>::from_uninitialized_ptr(ptr, len, queue_type).into(),
-        504 => Queue::<[u8; 504]>::from_uninitialized_ptr(ptr, len, queue_type).into(),
-        1016 => Queue::<[u8; 1016]>::from_uninitialized_ptr(ptr, len, queue_type).into(),
-        2040 => Queue::<[u8; 2040]>::from_uninitialized_ptr(ptr, len, queue_type).into(),
-        4088 => Queue::<[u8; 4088]>::from_uninitialized_ptr(ptr, len, queue_type).into(),
+        56 => unsafe { Queue::<[u8; 56]>::from_uninitialized_ptr(ptr, len, queue_type) }.into(),
+        120 => unsafe { Queue::<[u8; 120]>::from_uninitialized_ptr(ptr, len, queue_type) }.into(),
+        248 => unsafe { Queue::<[u8; 248]>::from_uninitialized_ptr(ptr, len, queue_type) }.into(),
+        504 => unsafe { Queue::<[u8; 504]>::from_uninitialized_ptr(ptr, len, queue_type) }.into(),
+        1016 => unsafe { Queue::<[u8; 1016]>::from_uninitialized_ptr(ptr, len, queue_type) }.into(),
+        2040 => unsafe { Queue::<[u8; 2040]>::from_uninitialized_ptr(ptr, len, queue_type) }.into(),
+        4088 => unsafe { Queue::<[u8; 4088]>::from_uninitialized_ptr(ptr, len, queue_type) }.into(),
         _ => return FFIError::UnsupportedMessageSize,
     }
 }
+
+//ByteQueues
+#[no_mangle]
+pub extern "C" fn byte_queue_size_in_bytes(len: usize, queuesize_in_bytes: &mut usize) -> FFIError {
+    if !len.is_power_of_two() {
+        return FFIError::QueueLengthNotPowerTwo;
+    }
+    *queuesize_in_bytes = ByteQueue::size_of(len);
+    FFIError::Success
+}
+
+#[no_mangle]
+pub extern "C" fn init_byte_queue(ptr: *mut u8, queue_type: QueueType, len: usize) -> FFIError {
+    unsafe { ByteQueue::from_uninitialized_ptr(ptr, len, queue_type) }.into()
+}
+
+#[no_mangle]
+pub extern "C" fn init_byte_producer(
+    queue_ptr: *mut QueueHeader,
+    producer_ptr: *mut ByteProducer<'static>,
+) -> FFIError {
+    let q = match unsafe { ByteQueue::from_initialized_ptr(queue_ptr) } {
+        Ok(q) => q,
+        Err(e) => return e.into(),
+    };
+    unsafe { producer_ptr.write(ByteProducer::from(q)) };
+    FFIError::Success
+}
+
+#[no_mangle]
+pub extern "C" fn init_byte_consumer(
+    path: *const std::os::raw::c_char,
+    consumer_ptr: *mut ByteConsumer<'static>,
+) -> FFIError {
+    let Ok(p) = unsafe { std::ffi::CStr::from_ptr(path) }.to_str() else {
+        return FFIError::InvalidPath;
+    };
+    let q = match ByteQueue::open_shared(p) {
+        Ok(q) => q.leak(),
+        Err(e) => return e.into(),
+    };
+    unsafe { consumer_ptr.write(ByteConsumer::from(q)) };
+    FFIError::Success
+}
+
+#[no_mangle]
+pub extern "C" fn produce_bytes(producer: *mut ByteProducer<'static>, msg: *const u8, len: usize) -> FFIError {
+    let msg = unsafe { std::slice::from_raw_parts(msg, len) };
+    match unsafe { &mut (*producer) }.produce(msg) {
+        Ok(_) => FFIError::Success,
+        Err(e) => e.into(),
+    }
+}
+
+/// On BufferTooSmall len is set to the size of the message, which is not consumed
+#[no_mangle]
+pub extern "C" fn consume_bytes(
+    consumer: *mut ByteConsumer<'static>,
+    dest: *mut u8,
+    capacity: usize,
+    len: &mut usize,
+) -> FFIError {
+    let dest = unsafe { std::slice::from_raw_parts_mut(dest, capacity) };
+    let consumer = unsafe { &mut (*consumer) };
+    match consumer.try_consume_into(dest) {
+        Ok(l) => {
+            *len = l;
+            FFIError::Success
+        }
+        Err(ByteReadError::BufferTooSmall(l)) => {
+            *len = l;
+            FFIError::BufferTooSmall
+        }
+        Err(ByteReadError::Read(e)) => e.into(),
+    }
+}
+
 use ma_ffi_macro::ffi_msg;
 ffi_msg!(56);
 ffi_msg!(120);
@@ -19,14 +19,11 @@ pub fn ffi_msg(input: TokenStream) -> TokenStream {
         queue_ptr: *mut QueueHeader,
         producer_ptr: *mut Producer<'static, [u8; #msgname]>,
     ) -> FFIError {
-        let q = match Queue::from_initialized_ptr(queue_ptr) {
+        let q = match unsafe { Queue::from_initialized_ptr(queue_ptr) } {
             Ok(q) => q,
             Err(e) => return e.into(),
         };
-        unsafe {
-            (*producer_ptr).produced_first = 0;
-            (*producer_ptr).queue = q;
-         }
+        unsafe { producer_ptr.write(Producer::from(q)) };
         FFIError::Success
     }});
 
@@ -39,8 +36,13 @@ pub fn ffi_msg(input: TokenStream) -> TokenStream {
         path: *const std::os::raw::c_char,
         consumer_ptr: *mut Consumer<'static,[u8; #a]>,
     ) -> FFIError {
-        let p = unsafe{ std::ffi::CStr::from_ptr(path)}.to_str().unwrap();
-        let q = Queue::<[u8; #a]>::open_shared(p).unwrap();
+        let Ok(p) = unsafe{ std::ffi::CStr::from_ptr(path)}.to_str() else {
+            return FFIError::InvalidPath;
+        };
+        let q = match Queue::<[u8; #a]>::open_shared(p) {
+            Ok(q) => q.leak(),
+            Err(e) => return e.into(),
+        };
         Consumer::init_header(consumer_ptr, q);
         FFIError::Success
     }});
@@ -69,7 +71,7 @@ pub fn ffi_msg(input: TokenStream) -> TokenStream {
     ) -> FFIError {
         loop {
             match unsafe { &mut (*reader) }.try_consume(dest) {
-                Ok(()) => return FFIError::Success,
+                Ok(_) => return FFIError::Success,
                 Err(e) => return e.into(),
             }
         }
@@ -13,8 +13,8 @@ use criterion::{criterion_group, criterion_main, Bencher, BenchmarkId, Criterion
 use ma_time::{Duration, Instant, Nanos};
 
 fn consume_bench<const N_BYTES: usize>(b: &mut Bencher, n_contenders: usize) {
+    let q = &ma_queues::Queue::new(4096, ma_queues::QueueType::SPMC).unwrap();
     std::thread::scope(|s| {
-        let q = ma_queues::Queue::new(4096, ma_queues::QueueType::SPMC).unwrap();
         let done = Arc::new(AtomicBool::new(false));
         let done1 = done.clone();
         s.spawn(move || {
@@ -108,8 +108,8 @@ fn consume(c: &mut Criterion) {
 }
 // this is max contention
 fn produce_bench_spmc<const N_BYTES: usize>(b: &mut Bencher, n_contenders: usize) {
+    let q = &ma_queues::Queue::new(4096, ma_queues::QueueType::SPMC).unwrap();
     std::thread::scope(|s| {
-        let q = ma_queues::Queue::new(4096, ma_queues::QueueType::SPMC).unwrap();
         let done = Arc::new(AtomicBool::new(false));
         for i in 0..n_contenders {
             let done1 = done.clone();
@@ -195,12 +195,68 @@ fn produce_spmc(c: &mut Criterion) {
     }
 }
 
+// Producing batch_size messages one by one vs in one go through produce_batch
+fn produce_batch_bench<const N_BYTES: usize>(b: &mut Bencher,
+                                             typ: ma_queues::QueueType,
+                                             batch_size: usize,
+                                             batched: bool) {
+    let q = ma_queues::Queue::new(4096, typ).unwrap();
+    let mut lck = ma_queues::Producer::from(&q);
+    let msgs = vec![[0u8; N_BYTES]; batch_size];
+    core_affinity::set_for_current(CoreId { id: 0 });
+    b.iter(|| {
+         if batched {
+             lck.produce_batch(&msgs);
+         } else {
+             for m in &msgs {
+                 lck.produce(m);
+             }
+         }
+     });
+}
+
+fn produce_batch(c: &mut Criterion) {
+    for typ in [ma_queues::QueueType::SPMC, ma_queues::QueueType::MPMC] {
+        for batch_size in [1, 8, 32, 128] {
+            let mut group = c.benchmark_group(format!("produce_batch_{typ:?}_{batch_size}"));
+            group.throughput(criterion::Throughput::Elements(batch_size as u64));
+            for size in [8, 60, 252, 1020].iter() {
+                for batched in [false, true] {
+                    let id = BenchmarkId::new(if batched { "batch" } else { "single" }, size);
+                    group.bench_with_input(id, size, |b, &size| match size {
+                             8 => {
+                                 produce_batch_bench::<8>(b, typ, batch_size, batched);
+                             }
+                             60 => {
+                                 produce_batch_bench::<60>(b, typ, batch_size, batched);
+                             }
+                             252 => {
+                                 produce_batch_bench::<252>(b, typ, batch_size, batched);
+                             }
+                             1020 => {
+                                 produce_batch_bench::<1020>(b, typ, batch_size, batched);
+                             }
+                             _ => {}
+                         });
+                }
+            }
+            group.finish();
+        }
+    }
+}
+
 #[derive(Clone, Copy, Debug)]
 struct LatencyMsg<const N: usize> {
     tstamp: Instant,
     msg:    [u8; N],
 }
 
+impl<const N: usize> ma_queues::TypeHash for LatencyMsg<N> {
+    const TYPE_HASH: u64 = ma_queues::fingerprint::hash_fields("LatencyMsg",
+                                                                &[("tstamp", ma_queues::fingerprint::hash_str("Instant")),
+                                                                  ("msg", <[u8; N] as ma_queues::TypeHash>::TYPE_HASH)]);
+}
+
 impl<const N: usize> Default for LatencyMsg<N> {
     fn default() -> Self {
         Self { tstamp: Instant::now(), msg: [0; N] }
@@ -208,8 +264,8 @@ impl<const N: usize> Default for LatencyMsg<N> {
 }
 
 fn consume_latency_bench<const N_BYTES: usize>(b: &mut Bencher, n_contenders: usize) {
+    let q = &ma_queues::Queue::new(4096, ma_queues::QueueType::SPMC).unwrap();
     std::thread::scope(|s| {
-        let q = ma_queues::Queue::new(4096, ma_queues::QueueType::SPMC).unwrap();
         let done = Arc::new(AtomicBool::new(false));
         for i in 1..n_contenders {
             let mut lock2 = ma_queues::Consumer::from(q);
@@ -315,7 +371,7 @@ fn consume_latency(c: &mut Criterion) {
 criterion_group! {
     name=queues;
     config=Criterion::default();
-    targets = consume, produce_spmc, consume_latency,
+    targets = consume, produce_spmc, produce_batch, consume_latency,
 }
 
 criterion_main!(queues);
@@ -1,6 +1,7 @@
 use std::env;
 
 use ma_queues::{QueueError,
+    fingerprint::{hash_fields, TypeHash},
     queue::{Consumer, Queue, QueueHeader},
     seqlock::Seqlock,
 };
@@ -21,6 +22,8 @@ impl GenericQueue {
             if !(*ptr).is_initialized() {
                 return Err(QueueError::UnInitialized);
             }
+            // The element type isn't known here, only the layout of the header and slots
+            (*ptr).fingerprint().check_layout()?;
 
             println!("sizeof {}, elsize {}", (*ptr).size_of(), (*ptr).elsize());
             Ok(&*(std::ptr::slice_from_raw_parts_mut(ptr, (*ptr).size_of()) as *const Self))
@@ -92,9 +95,26 @@ pub enum QueueMessage<T> {
     Stop,
 }
 
+ma_queues::impl_type_hash!(Timestamp { ingestion_t: u64, exchange_t: u64 });
+ma_queues::impl_type_hash!(L2Update { timestamp: Timestamp, instrument_ids: u64, flags: u8, price: f64, volume: f64 });
+
+// The variants as fields, with their payloads as tuples
+impl<T: TypeHash> TypeHash for QueueMessage<T> {
+    const TYPE_HASH: u64 = hash_fields("QueueMessage",
+                                       &[("Data", <((u64, u64), T)>::TYPE_HASH),
+                                         ("TimeUpdate", <(u32, Timestamp)>::TYPE_HASH),
+                                         ("Stop", <()>::TYPE_HASH)]);
+}
+
 fn main() {
-    let q = Queue::<QueueMessage<L2Update>>::open_shared("/dev/shm/mantra/queues/L2Update").unwrap();
-    let mut c = Consumer::from(q);
+    let q = match Queue::<QueueMessage<L2Update>>::open_shared("/dev/shm/mantra/queues/L2Update") {
+        Ok(q) => q,
+        Err(e) => {
+            eprintln!("{e}");
+            return;
+        }
+    };
+    let mut c = Consumer::from(&q);
     for i in 0..131072 {
         eprintln!("{:?}", q.version_of(i));
     }
@@ -0,0 +1,187 @@
+//! [`futures_core::Stream`] over a queue, for tokio based tooling.
+use std::{
+    future::Future,
+    io,
+    pin::Pin,
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use futures_core::Stream;
+use tokio::time::{Instant, Sleep};
+
+use crate::{
+    handle::QueueHandle,
+    queue::{Consumer, Queue},
+    ReadError,
+};
+#[cfg(target_os = "linux")]
+use crate::pollable::PollableConsumer;
+
+/// How an [`AsyncConsumer`] finds out about new messages once the queue is empty
+#[derive(Debug, Clone, Copy)]
+pub enum Notification {
+    /// Checks the queue again after every interval, producers don't pay anything for it
+    Interval(Duration),
+    /// Woken up right away through the eventfd of a [`PollableConsumer`]
+    #[cfg(target_os = "linux")]
+    Eventfd,
+}
+
+enum Inner<T: 'static> {
+    Interval {
+        consumer: Consumer<'static, T>,
+        interval: Duration,
+        sleep:    Pin<Box<Sleep>>,
+        // Dropped last, consumer points into it
+        _handle:  Option<QueueHandle<T>>,
+    },
+    #[cfg(target_os = "linux")]
+    Eventfd(tokio::io::unix::AsyncFd<PollableConsumer<T>>),
+}
+
+/// Stream of the messages in a queue, built on [`Consumer::try_consume`].
+///
+/// After yielding [`ReadError::SpedPast`] it recovers like [`Consumer::consume`] and continues.
+/// Has to be created inside a tokio runtime.
+pub struct AsyncConsumer<T: 'static> {
+    inner: Inner<T>,
+}
+
+impl<T: Copy + 'static> AsyncConsumer<T> {
+    /// Takes over the handle, for heap queues the producers can use [`AsyncConsumer::queue`]
+    pub fn new(queue: QueueHandle<T>, notification: Notification) -> io::Result<Self> {
+        Self::start(queue.as_static(), Some(queue), notification)
+    }
+
+    pub fn from_static(queue: &'static Queue<T>, notification: Notification) -> io::Result<Self> {
+        Self::start(queue, None, notification)
+    }
+
+    fn start(queue: &'static Queue<T>,
+             handle: Option<QueueHandle<T>>,
+             notification: Notification)
+             -> io::Result<Self> {
+        let inner = match notification {
+            Notification::Interval(interval) => Inner::Interval { consumer: Consumer::from(queue),
+                                                                  interval,
+                                                                  sleep: Box::pin(tokio::time::sleep(interval)),
+                                                                  _handle: handle },
+            #[cfg(target_os = "linux")]
+            Notification::Eventfd => {
+                let consumer = match handle {
+                    Some(handle) => PollableConsumer::new(handle)?,
+                    None => PollableConsumer::from_static(queue)?,
+                };
+                Inner::Eventfd(tokio::io::unix::AsyncFd::new(consumer)?)
+            }
+        };
+        Ok(Self { inner })
+    }
+
+    pub fn queue(&self) -> &Queue<T> {
+        match &self.inner {
+            Inner::Interval { consumer, .. } => consumer.queue,
+            #[cfg(target_os = "linux")]
+            Inner::Eventfd(fd) => fd.get_ref().queue(),
+        }
+    }
+
+    /// The count of the next message this consumer will read
+    pub fn count(&self) -> usize {
+        match &self.inner {
+            Inner::Interval { consumer, .. } => consumer.count(),
+            #[cfg(target_os = "linux")]
+            Inner::Eventfd(fd) => fd.get_ref().count(),
+        }
+    }
+}
+
+impl<T: Copy + 'static> Stream for AsyncConsumer<T> {
+    type Item = Result<T, ReadError>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        match &mut self.get_mut().inner {
+            Inner::Interval { consumer, interval, sleep, .. } => loop {
+                match consumer.try_consume_with(|m| *m) {
+                    Ok(m) => return Poll::Ready(Some(Ok(m))),
+                    Err(ReadError::Empty) => {}
+                    Err(e) => {
+                        consumer.recover_after_error();
+                        return Poll::Ready(Some(Err(e)));
+                    }
+                }
+                if sleep.as_mut().poll(cx).is_pending() {
+                    return Poll::Pending;
+                }
+                sleep.as_mut().reset(Instant::now() + *interval);
+            },
+            #[cfg(target_os = "linux")]
+            Inner::Eventfd(fd) => loop {
+                match fd.get_mut().try_consume_with(|m| *m) {
+                    Ok(m) => return Poll::Ready(Some(Ok(m))),
+                    Err(ReadError::Empty) => {}
+                    Err(e) => {
+                        fd.get_mut().recover_after_error();
+                        return Poll::Ready(Some(Err(e)));
+                    }
+                }
+                match fd.poll_read_ready_mut(cx) {
+                    Poll::Ready(Ok(mut guard)) => {
+                        // Anything produced after this signals the eventfd again
+                        guard.get_inner().clear();
+                        guard.clear_ready();
+                    }
+                    // The reactor is gone, nothing will wake us up anymore
+                    Poll::Ready(Err(_)) => return Poll::Ready(None),
+                    Poll::Pending => return Poll::Pending,
+                }
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{Producer, QueueType};
+
+    async fn next<S: Stream + Unpin>(s: &mut S) -> Option<S::Item> {
+        std::future::poll_fn(|cx| Pin::new(&mut *s).poll_next(cx)).await
+    }
+
+    fn stream(notification: Notification) {
+        let rt = tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap();
+        let q = Queue::<usize>::new(16, QueueType::SPSC).unwrap().leak();
+        let mut c = rt.block_on(async { AsyncConsumer::from_static(q, notification) }).unwrap();
+        let producer = std::thread::spawn(move || {
+            let mut p = Producer::from(q);
+            for i in 0..20 {
+                if i % 5 == 0 {
+                    std::thread::sleep(Duration::from_millis(5));
+                }
+                p.produce(&i);
+            }
+        });
+        let got = rt.block_on(async {
+                        let mut got = Vec::new();
+                        while got.len() < 20 {
+                            got.push(next(&mut c).await.unwrap().unwrap());
+                        }
+                        got
+                    });
+        producer.join().unwrap();
+        assert_eq!(got, (0..20).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn interval() {
+        stream(Notification::Interval(Duration::from_millis(1)));
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn eventfd() {
+        stream(Notification::Eventfd);
+    }
+}
@@ -0,0 +1,350 @@
+//! Queue of variable length messages.
+//!
+//! Messages are stored as length prefixed records spanning consecutive slots of a regular
+//! [`Queue`], so the same seqlock versions detect overwrites and it can be shared and opened
+//! like any other queue. A slot is exactly one cacheline, the first slot of a record starts with
+//! the length of the message as a little endian u32.
+use std::mem::MaybeUninit;
+
+use crate::{
+    handle::QueueHandle,
+    queue::{Consumer, Producer, Queue},
+    wait::pause,
+    ByteReadError, ReadError, WriteError,
+};
+
+pub const CHUNK_SIZE: usize = 56;
+pub type Chunk = [u8; CHUNK_SIZE];
+pub type ByteQueue = Queue<Chunk>;
+
+const LEN_SIZE: usize = 4;
+const FIRST_PAYLOAD: usize = CHUNK_SIZE - LEN_SIZE;
+
+/// Number of slots a message of len bytes occupies
+pub const fn n_chunks(len: usize) -> usize {
+    if len <= FIRST_PAYLOAD {
+        1
+    } else {
+        1 + (len - FIRST_PAYLOAD).div_ceil(CHUNK_SIZE)
+    }
+}
+
+#[repr(transparent)]
+pub struct ByteProducer<'a> {
+    producer: Producer<'a, Chunk>,
+}
+
+impl<'a> From<&'a QueueHandle<Chunk>> for ByteProducer<'a> {
+    fn from(queue: &'a QueueHandle<Chunk>) -> Self {
+        Self::from(&**queue)
+    }
+}
+
+impl<'a> From<&'a ByteQueue> for ByteProducer<'a> {
+    fn from(queue: &'a ByteQueue) -> Self {
+        Self { producer: Producer::from(queue) }
+    }
+}
+
+impl<'a> ByteProducer<'a> {
+    /// Nonblocking produce, returning the count of the first slot of the message.
+    /// Lossless queues return [`WriteError::Full`] if there is not enough room for the whole message.
+    pub fn try_produce(&mut self, msg: &[u8]) -> Result<usize, WriteError> {
+        let q = self.producer.queue;
+        let n = n_chunks(msg.len());
+        if n > q.len() || msg.len() > u32::MAX as usize {
+            return Err(WriteError::TooLarge);
+        }
+        let c = if q.is_lossless() {
+            q.try_next_count(&mut self.producer.read_count, n)?
+        } else {
+            q.next_count(n)
+        };
+        self.write_record(c, n, msg);
+        Ok(c)
+    }
+
+    /// Produces msg. On lossless queues this blocks until there is room for it.
+    pub fn produce(&mut self, msg: &[u8]) -> Result<usize, WriteError> {
+        loop {
+            match self.try_produce(msg) {
+                Err(WriteError::Full) => {
+                    pause();
+                }
+                r => return r,
+            }
+        }
+    }
+
+    // The first slot is written last, so once a consumer sees it the rest of the message is there too
+    fn write_record(&mut self, c: usize, n: usize, msg: &[u8]) {
+        let q = self.producer.queue;
+        let first = self.producer.produced_first == 0;
+        self.producer.produced_first = 1;
+        for i in (1..n).rev() {
+            let start = FIRST_PAYLOAD + (i - 1) * CHUNK_SIZE;
+            let part = &msg[start..msg.len().min(start + CHUNK_SIZE)];
+            write_slot(q, first, c.wrapping_add(i), |slot| unsafe {
+                (slot.as_mut_ptr() as *mut u8).copy_from_nonoverlapping(part.as_ptr(), part.len());
+            });
+        }
+        let part = &msg[..msg.len().min(FIRST_PAYLOAD)];
+        write_slot(q, first, c, |slot| unsafe {
+            let ptr = slot.as_mut_ptr() as *mut u8;
+            (ptr as *mut [u8; LEN_SIZE]).write_unaligned((msg.len() as u32).to_le_bytes());
+            ptr.add(LEN_SIZE).copy_from_nonoverlapping(part.as_ptr(), part.len());
+        });
+        q.notify();
+    }
+}
+
+// Through the queue, so slots get stamped and a producer's first writes unlock slots a crashed one left locked
+fn write_slot<F: FnOnce(&mut MaybeUninit<Chunk>)>(q: &ByteQueue, first: bool, c: usize, f: F) {
+    if first {
+        q.write_first_with(c, f)
+    } else {
+        q.write_with(c, f)
+    }
+}
+
+#[repr(transparent)]
+pub struct ByteConsumer<'a> {
+    consumer: Consumer<'a, Chunk>,
+}
+
+impl<'a> From<&'a QueueHandle<Chunk>> for ByteConsumer<'a> {
+    fn from(queue: &'a QueueHandle<Chunk>) -> Self {
+        Self::from(&**queue)
+    }
+}
+
+impl<'a> From<&'a ByteQueue> for ByteConsumer<'a> {
+    fn from(queue: &'a ByteQueue) -> Self {
+        Self { consumer: Consumer::from(queue) }
+    }
+}
+
+impl<'a> ByteConsumer<'a> {
+    /// Nonblocking consume into buf, which gets resized to the message. Returns the length of the message.
+    pub fn try_consume(&mut self, buf: &mut Vec<u8>) -> Result<usize, ReadError> {
+        self.read_record(usize::MAX, |offset, part| {
+                buf.truncate(offset);
+                buf.extend_from_slice(part);
+            })
+    }
+
+    /// Nonblocking consume into buf, returning [`ByteReadError::BufferTooSmall`] with the length of the
+    /// message, without consuming it, if it doesn't fit. Returns the length of the message.
+    pub fn try_consume_into(&mut self, buf: &mut [u8]) -> Result<usize, ByteReadError> {
+        let max_len = buf.len();
+        let len = self.read_record(max_len, |offset, part| buf[offset..offset + part.len()].copy_from_slice(part))?;
+        if len > max_len {
+            return Err(ByteReadError::BufferTooSmall(len));
+        }
+        Ok(len)
+    }
+
+    /// Blocking consume. After getting sped past it continues from the newest message.
+    pub fn consume(&mut self, buf: &mut Vec<u8>) -> usize {
+        loop {
+            match self.try_consume(buf) {
+                Ok(len) => return len,
+                Err(ReadError::SpedPast) => self.recover_after_error(),
+                Err(_) => {
+                    pause();
+                }
+            }
+        }
+    }
+
+    /// The length of the next message, without consuming it
+    pub fn peek_len(&self) -> Result<usize, ReadError> {
+        let q = self.consumer.queue;
+        let c = self.consumer.count();
+        q.consume_with(read_len, c & (q.len() - 1), q.version_for(c))
+    }
+
+    /// Slots after the one we got sped past on may be in the middle of a message,
+    /// the producer's count on the other hand is always at the start of one.
+    pub fn recover_after_error(&mut self) {
+        self.consumer.set_count(self.consumer.queue.count());
+    }
+
+    // sink receives the message in parts together with their offset. It may see torn data, in which
+    // case an error is returned after, and it is called again from the start the next time.
+    // Messages longer than max_len are left in the queue, only their length is returned.
+    fn read_record<F: FnMut(usize, &[u8])>(&mut self, max_len: usize, mut sink: F) -> Result<usize, ReadError> {
+        let q = self.consumer.queue;
+        let mask = q.len() - 1;
+        let c = self.consumer.count();
+        let len = q.consume_with(|chunk| {
+                                     let len = read_len(chunk);
+                                     if len <= max_len {
+                                         sink(0, &chunk[LEN_SIZE..LEN_SIZE + len.min(FIRST_PAYLOAD)]);
+                                     }
+                                     len
+                                 },
+                                 c & mask,
+                                 q.version_for(c))?;
+        if len > max_len {
+            return Ok(len);
+        }
+        let n = n_chunks(len);
+        for i in 1..n {
+            let start = FIRST_PAYLOAD + (i - 1) * CHUNK_SIZE;
+            let end = len.min(start + CHUNK_SIZE);
+            let ci = c.wrapping_add(i);
+            q.consume_with(|chunk| sink(start, &chunk[..end - start]), ci & mask, q.version_for(ci))?;
+        }
+        self.consumer.set_count(c.wrapping_add(n));
+        Ok(len)
+    }
+}
+
+fn read_len(chunk: &Chunk) -> usize {
+    u32::from_le_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]) as usize
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{Clock, QueueType};
+
+    fn msg(i: usize) -> Vec<u8> {
+        vec![i as u8; (i * 7) % 300]
+    }
+
+    #[test]
+    fn n_chunks_test() {
+        assert_eq!(n_chunks(0), 1);
+        assert_eq!(n_chunks(FIRST_PAYLOAD), 1);
+        assert_eq!(n_chunks(FIRST_PAYLOAD + 1), 2);
+        assert_eq!(n_chunks(FIRST_PAYLOAD + CHUNK_SIZE), 2);
+        assert_eq!(n_chunks(FIRST_PAYLOAD + CHUNK_SIZE + 1), 3);
+    }
+
+    #[test]
+    fn basic() {
+        for typ in [QueueType::SPMC, QueueType::MPMC, QueueType::SPSC, QueueType::MPSC] {
+            let q = ByteQueue::new(64, typ).unwrap();
+            let mut p = ByteProducer::from(&q);
+            let mut c = ByteConsumer::from(&q);
+            let mut buf = Vec::new();
+            assert_eq!(c.try_consume(&mut buf), Err(ReadError::Empty));
+
+            for i in 0..10 {
+                p.produce(&msg(i)).unwrap();
+            }
+            for i in 0..10 {
+                assert_eq!(c.peek_len(), Ok(msg(i).len()));
+                assert_eq!(c.try_consume(&mut buf), Ok(msg(i).len()));
+                assert_eq!(buf, msg(i));
+            }
+            assert_eq!(c.try_consume(&mut buf), Err(ReadError::Empty));
+            assert_eq!(p.try_produce(&[0; 64 * CHUNK_SIZE]), Err(WriteError::TooLarge));
+
+            p.produce(&msg(20)).unwrap();
+            let mut small = [0; 10];
+            assert_eq!(c.try_consume_into(&mut small), Err(ByteReadError::BufferTooSmall(msg(20).len())));
+            let mut large = [0; 300];
+            assert_eq!(c.try_consume_into(&mut large), Ok(msg(20).len()));
+            assert_eq!(&large[..msg(20).len()], &msg(20));
+
+            if q.is_lossless() {
+                // Everything is consumed so the message can take up the whole queue
+                let full = [1; FIRST_PAYLOAD + 63 * CHUNK_SIZE];
+                let c0 = q.count();
+                assert_eq!(p.try_produce(&full), Ok(c0));
+                assert_eq!(p.try_produce(&[]), Err(WriteError::Full));
+                assert_eq!(c.try_consume(&mut buf), Ok(full.len()));
+                assert_eq!(p.try_produce(&full), Ok(c0 + 64));
+            } else {
+                for i in 0..100 {
+                    p.produce(&msg(i)).unwrap();
+                }
+                assert_eq!(c.try_consume(&mut buf), Err(ReadError::SpedPast));
+                c.recover_after_error();
+                p.produce(&msg(30)).unwrap();
+                assert_eq!(c.try_consume(&mut buf), Ok(msg(30).len()));
+                assert_eq!(buf, msg(30));
+            }
+        }
+    }
+
+    #[test]
+    fn stamped_and_unpoisoned() {
+        let q = ByteQueue::new_with_clock(64, QueueType::SPMC, Clock::Monotonic).unwrap();
+        // A producer that died while writing leaves the slot locked
+        let _ = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| q.load(0).write_with(|_| panic!())));
+        let mut slots = Consumer::from(&q);
+        let mut c = ByteConsumer::from(&q);
+        let mut p = ByteProducer::from(&q);
+        p.produce(&msg(20)).unwrap();
+        let mut buf = Vec::new();
+        assert_eq!(c.try_consume(&mut buf), Ok(msg(20).len()));
+        assert_eq!(buf, msg(20));
+        let mut chunk = [0; CHUNK_SIZE];
+        for i in 0..n_chunks(msg(20).len()) {
+            let (seq, stamp) = slots.try_consume_stamped(&mut chunk).unwrap();
+            assert_eq!(seq, i);
+            assert_ne!(stamp, 0);
+        }
+    }
+
+    fn multithread(typ: QueueType, n_writers: usize, tot_messages: usize) {
+        let q = ByteQueue::new(256, typ).unwrap();
+        std::thread::scope(|s| {
+            let mut c1 = ByteConsumer::from(&q);
+            let cons = s.spawn(move || {
+                let mut buf = Vec::new();
+                let mut n = 0;
+                while n < tot_messages {
+                    c1.consume(&mut buf);
+                    // Torn messages would have mixed contents or the wrong length
+                    assert!(buf.iter().all(|b| *b == buf[0]));
+                    assert_eq!(msg(buf.first().copied().unwrap_or(0) as usize).len(), buf.len());
+                    n += 1;
+                }
+            });
+            let mut writehandles = Vec::new();
+            for _ in 0..n_writers {
+                let mut p1 = ByteProducer::from(&q);
+                writehandles.push(s.spawn(move || {
+                    for i in 0..tot_messages {
+                        p1.produce(&msg(i % 256)).unwrap();
+                        std::thread::yield_now();
+                    }
+                }));
+            }
+            for h in writehandles {
+                h.join().unwrap();
+            }
+            cons.join().unwrap();
+        });
+    }
+
+    #[test]
+    fn multithread_spsc() {
+        multithread(QueueType::SPSC, 1, 10000);
+    }
+    #[test]
+    fn multithread_mpmc() {
+        multithread(QueueType::MPMC, 4, 10000);
+    }
+
+    #[test]
+    #[cfg(feature = "shmem")]
+    fn basic_shared() {
+        let path = std::path::Path::new("/dev/shm/byte_queue_test");
+        let _ = std::fs::remove_file(path);
+        let q = ByteQueue::shared(path, 64, QueueType::SPMC).unwrap();
+        let mut p = ByteProducer::from(&q);
+        let q2 = ByteQueue::open_shared(path).unwrap();
+        let mut c = ByteConsumer::from(&q2);
+        let mut buf = Vec::new();
+        p.produce(&msg(100)).unwrap();
+        assert_eq!(c.try_consume(&mut buf), Ok(msg(100).len()));
+        assert_eq!(buf, msg(100));
+        ByteQueue::unlink(path).unwrap();
+    }
+}
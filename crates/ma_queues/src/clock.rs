@@ -0,0 +1,35 @@
+/// What producers stamp messages with, for queues created with timestamps.
+/// Only stamps taken with the same clock on the same machine can be compared.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[repr(u8)]
+pub enum Clock {
+    /// No timestamps, the default
+    #[default]
+    None,
+    /// CLOCK_MONOTONIC in nanoseconds, the same for all processes
+    Monotonic,
+    /// Cycles of the time stamp counter, cheaper to read but in cycles instead of nanoseconds.
+    /// Falls back to [`Clock::Monotonic`] on anything but x86_64.
+    Tsc,
+}
+
+impl Clock {
+    /// The current time according to this clock, 0 for [`Clock::None`]
+    #[inline(always)]
+    pub fn now(self) -> u64 {
+        match self {
+            Clock::None => 0,
+            Clock::Monotonic => monotonic_nanos(),
+            #[cfg(target_arch = "x86_64")]
+            Clock::Tsc => unsafe { std::arch::x86_64::_rdtsc() },
+            #[cfg(not(target_arch = "x86_64"))]
+            Clock::Tsc => monotonic_nanos(),
+        }
+    }
+}
+
+pub(crate) fn monotonic_nanos() -> u64 {
+    let mut ts = libc::timespec { tv_sec: 0, tv_nsec: 0 };
+    unsafe { libc::clock_gettime(libc::CLOCK_MONOTONIC, &mut ts) };
+    ts.tv_sec as u64 * 1_000_000_000 + ts.tv_nsec as u64
+}
@@ -0,0 +1,175 @@
+use std::cell::UnsafeCell;
+use std::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
+
+use crate::{heartbeat::pid_exists, wait::pause, QueueError};
+
+/// Number of named cursors that fit in a queue header
+pub const MAX_CURSORS: usize = 8;
+/// Max length in bytes of a cursor name
+pub const MAX_NAME_LEN: usize = 40;
+
+// Any other state is the pid of the process claiming the cursor
+const FREE: u32 = 0;
+const IN_USE: u32 = u32::MAX;
+
+/// A named read position living in the queue header, so it's shared by everyone
+/// that opens the queue, also across processes. Used by consumer groups and named consumers,
+/// which share the same names.
+#[derive(Debug)]
+#[repr(C, align(64))]
+pub struct Cursor {
+    state:     AtomicU32,                      // 4
+    _pad:      [u8; 4],                        // 8
+    count:     AtomicUsize,                    // 16
+    sped_past: AtomicUsize,                    // 24
+    // Only written while claiming
+    name:      UnsafeCell<[u8; MAX_NAME_LEN]>, // 64
+}
+unsafe impl Sync for Cursor {}
+
+impl Cursor {
+    pub(crate) fn reset(&mut self) {
+        self.state = AtomicU32::new(FREE);
+        self.count = AtomicUsize::new(0);
+        self.sped_past = AtomicUsize::new(0);
+        self.name = UnsafeCell::new([0; MAX_NAME_LEN]);
+    }
+
+    fn name_bytes(&self) -> &[u8; MAX_NAME_LEN] {
+        unsafe { &*self.name.get() }
+    }
+
+    pub fn name(&self) -> &str {
+        let name = self.name_bytes();
+        let len = name.iter().position(|&b| b == 0).unwrap_or(MAX_NAME_LEN);
+        std::str::from_utf8(&name[..len]).unwrap_or_default()
+    }
+
+    /// Count of the next message to be read through this cursor
+    pub fn count(&self) -> usize {
+        self.count.load(Ordering::Acquire)
+    }
+
+    /// How many times the producers lapped this cursor
+    pub fn sped_past(&self) -> usize {
+        self.sped_past.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn store(&self, c: usize) {
+        self.count.store(c, Ordering::Release)
+    }
+
+    pub(crate) fn compare_exchange(&self, current: usize, new: usize) -> Result<usize, usize> {
+        self.count.compare_exchange(current, new, Ordering::AcqRel, Ordering::Acquire)
+    }
+
+    pub(crate) fn mark_sped_past(&self) {
+        self.sped_past.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn is_named(&self, name: &[u8]) -> bool {
+        let own = self.name_bytes();
+        own[..name.len()] == *name && own.get(name.len()).copied().unwrap_or(0) == 0
+    }
+
+    /// Claims the cursor if it's free or its claimer died before finishing the claim,
+    /// otherwise waits until it's in use.
+    fn try_claim(&self, pid: u32) -> bool {
+        let mut state = FREE;
+        loop {
+            match self.state.compare_exchange(state, pid, Ordering::AcqRel, Ordering::Acquire) {
+                Ok(_) => return true,
+                Err(IN_USE) => return false,
+                Err(claimer) if claimer != FREE && !pid_exists(claimer) => state = claimer,
+                Err(_) => {
+                    state = FREE;
+                    pause();
+                }
+            }
+        }
+    }
+
+    fn claim(&self, name: &[u8], count: usize) {
+        // Nobody reads the name until the state is IN_USE
+        let own = unsafe { &mut *self.name.get() };
+        *own = [0; MAX_NAME_LEN];
+        own[..name.len()].copy_from_slice(name);
+        self.count.store(count, Ordering::Relaxed);
+        self.sped_past.store(0, Ordering::Relaxed);
+        self.state.store(IN_USE, Ordering::Release);
+    }
+}
+
+/// Finds the cursor called name, or claims a free one starting at the count returned by start.
+///
+/// Everyone scans the table in the same order and waits on cursors that are being claimed,
+/// so two processes opening the same name at the same time end up with the same cursor.
+/// A cursor whose claimer died halfway is taken over by the next process that gets to it.
+pub(crate) fn find_or_claim<'a, F: FnOnce() -> usize>(cursors: &'a [Cursor],
+                                                      name: &str,
+                                                      start: F)
+                                                      -> Result<&'a Cursor, QueueError> {
+    let name = name.as_bytes();
+    if name.is_empty() || name.len() > MAX_NAME_LEN || name.contains(&0) {
+        return Err(QueueError::InvalidCursorName);
+    }
+    let pid = std::process::id();
+    for cursor in cursors {
+        if cursor.try_claim(pid) {
+            cursor.claim(name, start());
+            return Ok(cursor);
+        }
+        if cursor.is_named(name) {
+            return Ok(cursor);
+        }
+    }
+    Err(QueueError::CursorTableFull)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn table() -> Vec<Cursor> {
+        (0..MAX_CURSORS).map(|_| unsafe { std::mem::zeroed() }).collect()
+    }
+
+    #[test]
+    fn size() {
+        assert_eq!(64, std::mem::size_of::<Cursor>());
+    }
+
+    #[test]
+    fn claim() {
+        let cursors = table();
+        let a = find_or_claim(&cursors, "risk", || 10).unwrap();
+        assert_eq!(a.name(), "risk");
+        assert_eq!(a.count(), 10);
+        let b = find_or_claim(&cursors, "risk", || 20).unwrap();
+        assert!(std::ptr::eq(a, b));
+        assert_eq!(b.count(), 10);
+        let c = find_or_claim(&cursors, "ris", || 0).unwrap();
+        assert!(!std::ptr::eq(a, c));
+
+        assert!(matches!(find_or_claim(&cursors, "", || 0), Err(QueueError::InvalidCursorName)));
+        let long = "a".repeat(MAX_NAME_LEN + 1);
+        assert!(matches!(find_or_claim(&cursors, &long, || 0), Err(QueueError::InvalidCursorName)));
+
+        for i in 2..MAX_CURSORS {
+            find_or_claim(&cursors, &i.to_string(), || 0).unwrap();
+        }
+        assert!(matches!(find_or_claim(&cursors, "full", || 0), Err(QueueError::CursorTableFull)));
+    }
+
+    #[test]
+    fn claimer_died() {
+        let cursors = table();
+        let mut child = std::process::Command::new("true").spawn().unwrap();
+        let dead = child.id();
+        child.wait().unwrap();
+        cursors[0].state.store(dead, Ordering::Relaxed);
+        let a = find_or_claim(&cursors, "risk", || 10).unwrap();
+        assert!(std::ptr::eq(a, &cursors[0]));
+        assert_eq!((a.name(), a.count()), ("risk", 10));
+    }
+}
@@ -0,0 +1,185 @@
+use crate::QueueError;
+
+/// Marks shared memory as holding one of our queues or vectors
+pub const MAGIC: u32 = u32::from_le_bytes(*b"MAQ\0");
+/// Bump whenever the layout of the headers or the slots changes
+pub const LAYOUT_VERSION: u32 = 8;
+
+/// Structural hash of a message type, so a queue or vector can only be opened with the type it
+/// was created with. It only depends on the names of the type and its fields and on the hashes
+/// of the field types, so processes built from different crates can share a queue through their
+/// own copy of a message type. Implement it with [`impl_type_hash!`](crate::impl_type_hash),
+/// or by hand with [`hash_fields`] for generic types.
+pub trait TypeHash {
+    const TYPE_HASH: u64;
+}
+
+macro_rules! impl_type_hash_primitive {
+    ($($t:ty),*) => { $(impl TypeHash for $t { const TYPE_HASH: u64 = hash_str(stringify!($t)); })* };
+}
+impl_type_hash_primitive!(bool, char, u8, u16, u32, u64, u128, usize, i8, i16, i32, i64, i128, isize, f32, f64);
+
+impl<T: TypeHash, const N: usize> TypeHash for [T; N] {
+    const TYPE_HASH: u64 = combine(combine(hash_str("[]"), T::TYPE_HASH), N as u64);
+}
+
+macro_rules! impl_type_hash_tuple {
+    ($($t:ident),*) => {
+        impl<$($t: TypeHash),*> TypeHash for ($($t,)*) {
+            const TYPE_HASH: u64 = hash_fields("()", &[$(("", $t::TYPE_HASH)),*]);
+        }
+    };
+}
+impl_type_hash_tuple!();
+impl_type_hash_tuple!(A);
+impl_type_hash_tuple!(A, B);
+impl_type_hash_tuple!(A, B, C);
+impl_type_hash_tuple!(A, B, C, D);
+
+/// Implements [`TypeHash`] for a struct from its name and fields, which have to be listed in order:
+/// ```
+/// #[derive(Clone, Copy)]
+/// struct Trade { price: f64, size: u32 }
+/// ma_queues::impl_type_hash!(Trade { price: f64, size: u32 });
+/// ```
+#[macro_export]
+macro_rules! impl_type_hash {
+    ($name:ident { $($field:ident: $t:ty),* $(,)? }) => {
+        impl $crate::fingerprint::TypeHash for $name {
+            const TYPE_HASH: u64 = $crate::fingerprint::hash_fields(
+                stringify!($name),
+                &[$((stringify!($field), <$t as $crate::fingerprint::TypeHash>::TYPE_HASH)),*],
+            );
+        }
+    };
+}
+
+/// Hash of a struct with fields given as name and [`TypeHash::TYPE_HASH`] of their type, in order
+pub const fn hash_fields(name: &str, fields: &[(&str, u64)]) -> u64 {
+    let mut h = hash_str(name);
+    let mut i = 0;
+    while i < fields.len() {
+        h = combine(combine(h, hash_str(fields[i].0)), fields[i].1);
+        i += 1;
+    }
+    h
+}
+
+/// fnv1a, the same on every platform and compiler version
+pub const fn hash_str(s: &str) -> u64 {
+    let bytes = s.as_bytes();
+    let mut h = 0xcbf29ce484222325u64;
+    let mut i = 0;
+    while i < bytes.len() {
+        h = (h ^ bytes[i] as u64).wrapping_mul(0x100000001b3);
+        i += 1;
+    }
+    h
+}
+
+const fn combine(h: u64, v: u64) -> u64 {
+    let bytes = v.to_le_bytes();
+    let mut h = h;
+    let mut i = 0;
+    while i < bytes.len() {
+        h = (h ^ bytes[i] as u64).wrapping_mul(0x100000001b3);
+        i += 1;
+    }
+    h
+}
+
+/// Stored in the header on creation and checked on open, so a queue or vector
+/// can't be opened with a different element type, see [`TypeHash`], or by an incompatible
+/// version of this crate.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(C)]
+pub struct Fingerprint {
+    pub magic:          u32, // 4
+    pub layout_version: u32, // 8
+    pub size:           u32, // 12
+    pub align:          u32, // 16
+    pub type_hash:      u64, // 24
+}
+
+impl Fingerprint {
+    pub fn of<T: TypeHash>() -> Self {
+        Self {
+            magic:          MAGIC,
+            layout_version: LAYOUT_VERSION,
+            size:           std::mem::size_of::<T>() as u32,
+            align:          std::mem::align_of::<T>() as u32,
+            type_hash:      T::TYPE_HASH,
+        }
+    }
+
+    /// Only checks that the memory was initialized with our current layout, regardless of the element type
+    pub fn check_layout(&self) -> Result<(), QueueError> {
+        let expected = Self { size: self.size, align: self.align, type_hash: self.type_hash, ..Self::of::<u8>() };
+        self.check(expected)
+    }
+
+    pub fn check_type<T: TypeHash>(&self) -> Result<(), QueueError> {
+        self.check(Self::of::<T>())
+    }
+
+    fn check(&self, expected: Self) -> Result<(), QueueError> {
+        if *self == expected {
+            Ok(())
+        } else {
+            Err(QueueError::FingerprintMismatch { expected, found: *self })
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn check() {
+        let f = Fingerprint::of::<[u8; 56]>();
+        assert!(f.check_type::<[u8; 56]>().is_ok());
+        assert!(f.check_layout().is_ok());
+        assert!(f.check_type::<[u8; 48]>().is_err());
+        // Same size and alignment, different type
+        assert!(f.check_type::<[i8; 56]>().is_err());
+        assert!(f.check_type::<[u16; 28]>().is_err());
+        let old = Fingerprint { layout_version: 0, ..f };
+        assert!(old.check_layout().is_err());
+        let zeroed: Fingerprint = unsafe { std::mem::zeroed() };
+        assert!(matches!(zeroed.check_type::<[u8; 56]>(), Err(QueueError::FingerprintMismatch { .. })));
+    }
+
+    #[allow(dead_code)]
+    mod types {
+        pub struct Trade {
+            price: f64,
+            size:  u32,
+        }
+        crate::impl_type_hash!(Trade { price: f64, size: u32 });
+
+        pub struct Quote {
+            price: f64,
+            size:  u32,
+        }
+        crate::impl_type_hash!(Quote { price: f64, size: u32 });
+
+        pub mod other {
+            pub struct Trade {
+                price: f64,
+                size:  u32,
+            }
+            crate::impl_type_hash!(Trade { price: f64, size: u32 });
+        }
+    }
+
+    #[test]
+    fn structural() {
+        let f = Fingerprint::of::<types::Trade>();
+        assert!(f.check_type::<types::other::Trade>().is_ok());
+        assert!(f.check_type::<types::Quote>().is_err());
+        assert!(f.check_type::<(f64, u32)>().is_err());
+        assert_ne!(u64::TYPE_HASH, usize::TYPE_HASH);
+        assert_ne!(<[u8; 2]>::TYPE_HASH, <(u8, u8)>::TYPE_HASH);
+    }
+}
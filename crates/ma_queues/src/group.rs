@@ -0,0 +1,229 @@
+use crate::{cursor::Cursor, queue::Queue, wait::pause, QueueError, ReadError};
+
+/// Member of a consumer group. All members of a group, possibly in different processes, share a
+/// [`Cursor`] in the queue header and each message is handed to exactly one of them.
+///
+/// A member reads the message at the group's count and only then claims it by moving the cursor
+/// forward, so a message that was claimed has been read completely. On lossless queues the group
+/// takes the place of the single consumer.
+pub struct GroupConsumer<'a, T> {
+    cursor: &'a Cursor,
+    queue:  &'a Queue<T>,
+}
+
+impl<'a, T: Copy> GroupConsumer<'a, T> {
+    /// Joins the group called name, creating it if it doesn't exist yet.
+    /// A new group starts at the latest message, or at the first unread one for lossless queues.
+    pub fn join(queue: &'a Queue<T>, name: &str) -> Result<Self, QueueError> {
+        let start = || if queue.is_lossless() { queue.read_count() } else { queue.count() };
+        let cursor = crate::cursor::find_or_claim(queue.header.cursors(), name, start)?;
+        Ok(Self { cursor, queue })
+    }
+
+    /// Nonblocking consume of the group's next message, returning its sequence number.
+    ///
+    /// Returns [`ReadError::SpedPast`] to the member that notices the group got lapped. That member
+    /// also moves the group on to the latest message, so there's nothing to recover.
+    pub fn try_consume(&mut self, el: &mut T) -> Result<usize, ReadError> {
+        let mut c = self.cursor.count();
+        loop {
+            let pos = c & (self.queue.len() - 1);
+            match self.queue.consume(el, pos, self.queue.version_for(c)) {
+                Ok(()) => match self.cursor.compare_exchange(c, c + 1) {
+                    Ok(_) => {
+                        self.publish(c + 1);
+                        return Ok(c);
+                    }
+                    // Another member got it first
+                    Err(cur) => c = cur,
+                },
+                Err(ReadError::SpedPast) => {
+                    let latest = self.queue.count();
+                    match self.cursor.compare_exchange(c, latest) {
+                        Ok(_) => {
+                            self.cursor.mark_sped_past();
+                            self.publish(latest);
+                            return Err(ReadError::SpedPast);
+                        }
+                        // Either another member already recovered or our count was stale
+                        Err(cur) => c = cur,
+                    }
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Blocking consume, the group silently skips ahead when it got sped past
+    pub fn consume(&mut self, el: &mut T) -> usize {
+        loop {
+            match self.try_consume(el) {
+                Ok(c) => return c,
+                Err(ReadError::Empty) => {
+                    pause();
+                }
+                Err(ReadError::SpedPast) => {}
+            }
+        }
+    }
+
+    /// The count of the next message the group will hand out
+    pub fn count(&self) -> usize {
+        self.cursor.count()
+    }
+
+    /// How many times the group got sped past, summed over all members
+    pub fn sped_past(&self) -> usize {
+        self.cursor.sped_past()
+    }
+
+    pub fn name(&self) -> &str {
+        self.cursor.name()
+    }
+
+    fn publish(&self, c: usize) {
+        if self.queue.is_lossless() {
+            self.queue.advance_read_count(c);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    use super::*;
+    use crate::{Producer, QueueType};
+
+    #[test]
+    fn basic() {
+        let q = Queue::new(16, QueueType::MPMC).unwrap();
+        let mut p = Producer::from(&q);
+        let mut a = GroupConsumer::join(&q, "risk").unwrap();
+        let mut b = GroupConsumer::join(&q, "risk").unwrap();
+        let mut other = GroupConsumer::join(&q, "logging").unwrap();
+        let mut m = 0;
+        assert_eq!(a.try_consume(&mut m), Err(ReadError::Empty));
+        for i in 0..4 {
+            p.produce(&i);
+        }
+        a.try_consume(&mut m).unwrap();
+        assert_eq!(m, 0);
+        b.try_consume(&mut m).unwrap();
+        assert_eq!(m, 1);
+        a.try_consume(&mut m).unwrap();
+        assert_eq!(m, 2);
+        assert_eq!(b.count(), 3);
+        for i in 0..4 {
+            other.try_consume(&mut m).unwrap();
+            assert_eq!(m, i);
+        }
+        assert_eq!(other.try_consume(&mut m), Err(ReadError::Empty));
+    }
+
+    #[test]
+    fn sped_past() {
+        let q = Queue::new(16, QueueType::SPMC).unwrap();
+        let mut p = Producer::from(&q);
+        let mut a = GroupConsumer::join(&q, "g").unwrap();
+        let mut b = GroupConsumer::join(&q, "g").unwrap();
+        for i in 0..40 {
+            p.produce(&i);
+        }
+        let mut m = 0;
+        assert_eq!(a.try_consume(&mut m), Err(ReadError::SpedPast));
+        assert_eq!(b.sped_past(), 1);
+        assert_eq!(b.try_consume(&mut m), Err(ReadError::Empty));
+        p.produce(&40);
+        b.try_consume(&mut m).unwrap();
+        assert_eq!(m, 40);
+    }
+
+    #[test]
+    fn lossless() {
+        let q = Queue::new(4, QueueType::SPSC).unwrap();
+        let mut p = Producer::from(&q);
+        let mut a = GroupConsumer::join(&q, "g").unwrap();
+        let mut b = GroupConsumer::join(&q, "g").unwrap();
+        for i in 0..4 {
+            p.produce(&i);
+        }
+        assert_eq!(p.try_produce(&4), Err(crate::WriteError::Full));
+        let mut m = 0;
+        b.try_consume(&mut m).unwrap();
+        a.try_consume(&mut m).unwrap();
+        assert_eq!(m, 1);
+        p.try_produce(&4).unwrap();
+        p.try_produce(&5).unwrap();
+        assert_eq!(p.try_produce(&6), Err(crate::WriteError::Full));
+    }
+
+    fn member(mut c: GroupConsumer<'_, usize>, done: &AtomicBool) -> Vec<usize> {
+        let mut seen = Vec::new();
+        let mut m = 0;
+        loop {
+            // Loaded before consuming so Empty means everything was read
+            let finished = done.load(Ordering::Acquire);
+            match c.try_consume(&mut m) {
+                Ok(_) => seen.push(m),
+                Err(ReadError::Empty) if finished => return seen,
+                Err(ReadError::Empty) => std::thread::yield_now(),
+                Err(e) => panic!("{e}"),
+            }
+        }
+    }
+
+    fn multithread(typ: QueueType, n_members: usize, tot_messages: usize) {
+        let q = Queue::new(4096, typ).unwrap();
+        let done = AtomicBool::new(false);
+        let mut seen = std::thread::scope(|s| {
+            let members: Vec<_> = (0..n_members).map(|_| {
+                                                    let c = GroupConsumer::join(&q, "workers").unwrap();
+                                                    s.spawn(|| member(c, &done))
+                                                })
+                                                .collect();
+            let mut p = Producer::from(&q);
+            for i in 0..tot_messages {
+                p.produce(&i);
+                if i % 64 == 0 {
+                    std::thread::yield_now();
+                }
+            }
+            done.store(true, Ordering::Release);
+            members.into_iter().flat_map(|h| h.join().unwrap()).collect::<Vec<_>>()
+        });
+        seen.sort_unstable();
+        assert_eq!(seen, (0..tot_messages).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn multithread_mpmc() {
+        multithread(QueueType::MPMC, 4, 2000);
+    }
+
+    #[test]
+    fn multithread_spsc() {
+        multithread(QueueType::SPSC, 3, 20000);
+    }
+
+    #[test]
+    #[cfg(feature = "shmem")]
+    fn basic_shared() {
+        let path = std::path::Path::new("/dev/shm/group_basic_shared");
+        let _ = std::fs::remove_file(path);
+        let q = Queue::<usize>::shared(path, 16, QueueType::SPMC).unwrap();
+        let q2 = Queue::<usize>::open_shared(path).unwrap();
+        let mut p = Producer::from(&q);
+        let mut a = GroupConsumer::join(&q, "g").unwrap();
+        let mut b = GroupConsumer::join(&q2, "g").unwrap();
+        p.produce(&1);
+        p.produce(&2);
+        let mut m = 0;
+        b.try_consume(&mut m).unwrap();
+        assert_eq!(m, 1);
+        a.try_consume(&mut m).unwrap();
+        assert_eq!(m, 2);
+        assert_eq!(a.try_consume(&mut m), Err(ReadError::Empty));
+        Queue::<usize>::unlink(path).unwrap();
+    }
+}
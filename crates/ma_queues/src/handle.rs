@@ -0,0 +1,139 @@
+use std::{alloc::Layout, ops::Deref};
+
+use crate::{histogram::LatencyHistogram, queue::Queue, vector::SeqlockVector};
+
+pub type QueueHandle<T> = Handle<Queue<T>>;
+pub type VectorHandle<T> = Handle<SeqlockVector<T>>;
+pub type HistogramHandle = Handle<LatencyHistogram>;
+
+// What keeps the memory behind a handle alive
+enum Backing {
+    Heap(*mut u8, Layout),
+    #[cfg(feature = "shmem")]
+    Shmem(shared_memory::Shmem),
+}
+
+/// Owns the memory of a [`Queue`] or [`SeqlockVector`] and derefs to it.
+/// Heap memory is freed and shared memory is unmapped on drop, so producers and consumers
+/// borrow from the handle.
+///
+/// Shared memory is only removed on drop by handles that are the owner, see [`Handle::set_owner`].
+pub struct Handle<Q: ?Sized + 'static> {
+    // Only valid for as long as backing is alive
+    inner:        &'static Q,
+    backing:      Backing,
+    owner:        bool,
+    // Lets handles in other processes know the shared memory is being removed
+    mark_removed: fn(&Q),
+}
+
+unsafe impl<Q: ?Sized + Sync> Send for Handle<Q> {}
+unsafe impl<Q: ?Sized + Sync> Sync for Handle<Q> {}
+
+impl<Q: ?Sized> Handle<Q> {
+    pub(crate) fn heap(inner: &'static Q, ptr: *mut u8, layout: Layout) -> Self {
+        Self { inner, backing: Backing::Heap(ptr, layout), owner: false, mark_removed: |_| {} }
+    }
+
+    #[cfg(feature = "shmem")]
+    pub(crate) fn shmem(inner: &'static Q, shmem: shared_memory::Shmem, mark_removed: fn(&Q)) -> Self {
+        Self { inner, backing: Backing::Shmem(shmem), owner: false, mark_removed }
+    }
+
+    /// When owner, dropping the handle removes the shared memory, after marking it as removed
+    /// for the handles that still have it open. Handles start out not being the owner,
+    /// also the ones that created the shared memory. Does nothing for heap memory.
+    pub fn set_owner(&mut self, owner: bool) {
+        self.owner = owner;
+    }
+
+    pub fn is_owner(&self) -> bool {
+        self.owner
+    }
+
+    // For things that keep the handle alive themselves, e.g. next to a thread using the memory
+    pub(crate) fn as_static(&self) -> &'static Q {
+        self.inner
+    }
+
+    /// Gives up ownership, keeping the memory alive for the rest of the process, e.g. to pass it over FFI
+    pub fn leak(self) -> &'static Q {
+        let inner = self.inner;
+        std::mem::forget(self);
+        inner
+    }
+}
+
+impl<Q: ?Sized> Deref for Handle<Q> {
+    type Target = Q;
+
+    fn deref(&self) -> &Q {
+        self.inner
+    }
+}
+
+impl<Q: ?Sized> AsRef<Q> for Handle<Q> {
+    fn as_ref(&self) -> &Q {
+        self.inner
+    }
+}
+
+impl<Q: ?Sized> Drop for Handle<Q> {
+    fn drop(&mut self) {
+        match &mut self.backing {
+            Backing::Heap(ptr, layout) => unsafe { std::alloc::dealloc(*ptr, *layout) },
+            // Unmaps when dropped with self, and unlinks if owner
+            #[cfg(feature = "shmem")]
+            Backing::Shmem(shmem) => {
+                if self.owner {
+                    (self.mark_removed)(self.inner);
+                    shmem.set_owner(true);
+                }
+            }
+        }
+    }
+}
+
+impl<Q: ?Sized + std::fmt::Debug> std::fmt::Debug for Handle<Q> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.inner.fmt(f)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{Consumer, Producer, QueueType};
+
+    #[test]
+    fn heap() {
+        let q = Queue::<usize>::new(16, QueueType::SPMC).unwrap();
+        let mut p = Producer::from(&q);
+        let mut c = Consumer::from(&q);
+        p.produce(&3);
+        let mut m = 0;
+        c.try_consume(&mut m).unwrap();
+        assert_eq!(m, 3);
+        drop((p, c));
+        let v = SeqlockVector::<usize>::new(4);
+        v.write(1, &2);
+        assert_eq!(v.leak().read_copy(1), 2);
+    }
+
+    #[test]
+    #[cfg(feature = "shmem")]
+    fn shared_survives_drop() {
+        let path = std::path::Path::new("/dev/shm/handle_test");
+        let _ = std::fs::remove_file(path);
+        {
+            let q = Queue::<usize>::shared(path, 16, QueueType::SPMC).unwrap();
+            Producer::from(&q).produce(&42);
+        }
+        assert!(path.exists());
+        let q = Queue::<usize>::open_shared(path).unwrap();
+        let mut m = 0;
+        q.read(&mut m, 0);
+        assert_eq!(m, 42);
+        Queue::<usize>::unlink(path).unwrap();
+    }
+}
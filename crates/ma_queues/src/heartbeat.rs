@@ -0,0 +1,81 @@
+use std::{
+    sync::atomic::{AtomicU32, AtomicU64, Ordering},
+    time::Duration,
+};
+
+use crate::clock::monotonic_nanos;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProducerStatus {
+    /// No producer sent a heartbeat yet
+    Unknown,
+    Alive,
+    /// The last heartbeat is older than the allowed interval
+    Stale,
+    /// The process that sent the last heartbeat doesn't exist anymore
+    Dead,
+}
+
+/// Last sign of life of a producer, lives in the queue header.
+/// With multiple producers it holds whichever sent the last heartbeat.
+#[derive(Debug, Default)]
+#[repr(C)]
+pub struct Heartbeat {
+    nanos: AtomicU64, // 8  CLOCK_MONOTONIC, the same for all processes
+    pid:   AtomicU32, // 12
+    _pad:  [u8; 4],   // 16
+}
+
+impl Heartbeat {
+    pub(crate) fn beat(&self) {
+        self.pid.store(std::process::id(), Ordering::Relaxed);
+        self.nanos.store(monotonic_nanos(), Ordering::Release);
+    }
+
+    /// Pid of the producer that sent the last heartbeat and how long ago it was
+    pub fn last(&self) -> Option<(u32, Duration)> {
+        let nanos = self.nanos.load(Ordering::Acquire);
+        let pid = self.pid.load(Ordering::Relaxed);
+        if pid == 0 {
+            return None;
+        }
+        Some((pid, Duration::from_nanos(monotonic_nanos().saturating_sub(nanos))))
+    }
+
+    pub fn status(&self, stale_after: Duration) -> ProducerStatus {
+        match self.last() {
+            None => ProducerStatus::Unknown,
+            Some((pid, _)) if !pid_exists(pid) => ProducerStatus::Dead,
+            Some((_, since)) if since > stale_after => ProducerStatus::Stale,
+            Some(_) => ProducerStatus::Alive,
+        }
+    }
+}
+
+// Signal 0 only checks whether we could send one, EPERM means it exists but isn't ours
+pub(crate) fn pid_exists(pid: u32) -> bool {
+    let res = unsafe { libc::kill(pid as libc::pid_t, 0) };
+    res == 0 || std::io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn status() {
+        let hb: Heartbeat = unsafe { std::mem::zeroed() };
+        assert_eq!(hb.status(Duration::from_secs(1)), ProducerStatus::Unknown);
+        hb.beat();
+        assert_eq!(hb.last().unwrap().0, std::process::id());
+        assert_eq!(hb.status(Duration::from_secs(10)), ProducerStatus::Alive);
+        std::thread::sleep(Duration::from_millis(20));
+        assert_eq!(hb.status(Duration::from_millis(10)), ProducerStatus::Stale);
+
+        let mut child = std::process::Command::new("true").spawn().unwrap();
+        let pid = child.id();
+        child.wait().unwrap();
+        hb.pid.store(pid, Ordering::Relaxed);
+        assert_eq!(hb.status(Duration::from_secs(10)), ProducerStatus::Dead);
+    }
+}
@@ -0,0 +1,351 @@
+//! Latency histograms that can live in shared memory.
+//!
+//! Consumers [`LatencyHistogram::record`] produce to consume latencies, e.g. from the timestamps
+//! of [`Consumer::try_consume_stamped`](crate::Consumer::try_consume_stamped), and a monitor in
+//! another process takes [`LatencyHistogram::snapshot`]s. The histogram is behind a seqlock, so the
+//! monitor never blocks the consumers. Resets are only requested by the monitor and carried
+//! out by the next record.
+use std::{
+    alloc::Layout,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use crate::{
+    clock::Clock,
+    fingerprint::Fingerprint,
+    handle::{Handle, HistogramHandle},
+    seqlock::Seqlock,
+    QueueError,
+};
+
+/// Every power of two range is split in 2^SUB_BITS buckets, i.e. values are recorded with a
+/// relative error of at most 1/2^SUB_BITS (~3%)
+const SUB_BITS: u32 = 5;
+const SUB_BUCKETS: usize = 1 << SUB_BITS;
+/// Values with more significant bits than this, about 18 minutes in nanoseconds, end up in the last bucket
+const MAX_BITS: u32 = 40;
+pub const N_BUCKETS: usize = (MAX_BITS as usize - SUB_BITS as usize + 1) * SUB_BUCKETS;
+
+/// Log-linear histogram in the style of HdrHistogram, with a fixed size so it can live in shared memory.
+/// All zeros is an empty histogram.
+#[derive(Clone, Copy)]
+#[repr(C)]
+pub struct Histogram {
+    total:  u64,
+    min:    u64,
+    max:    u64,
+    sum:    u64,
+    counts: [u64; N_BUCKETS],
+}
+
+crate::impl_type_hash!(Histogram { total: u64, min: u64, max: u64, sum: u64, counts: [u64; N_BUCKETS] });
+
+impl Default for Histogram {
+    fn default() -> Self {
+        Self { total: 0, min: 0, max: 0, sum: 0, counts: [0; N_BUCKETS] }
+    }
+}
+
+fn bucket_of(value: u64) -> usize {
+    let value = value.min((1 << MAX_BITS) - 1);
+    if value < 2 * SUB_BUCKETS as u64 {
+        return value as usize;
+    }
+    let shift = 63 - value.leading_zeros() - SUB_BITS;
+    (shift as usize) * SUB_BUCKETS + (value >> shift) as usize
+}
+
+// The lowest value that ends up in bucket
+fn value_of(bucket: usize) -> u64 {
+    if bucket < 2 * SUB_BUCKETS {
+        return bucket as u64;
+    }
+    let shift = bucket / SUB_BUCKETS - 1;
+    ((bucket - shift * SUB_BUCKETS) as u64) << shift
+}
+
+impl Histogram {
+    pub fn record(&mut self, value: u64) {
+        self.min = if self.total == 0 { value } else { self.min.min(value) };
+        self.max = self.max.max(value);
+        self.total += 1;
+        self.sum = self.sum.wrapping_add(value);
+        self.counts[bucket_of(value)] += 1;
+    }
+
+    pub fn clear(&mut self) {
+        *self = Self::default();
+    }
+
+    /// Number of recorded values
+    pub fn count(&self) -> u64 {
+        self.total
+    }
+
+    pub fn min(&self) -> u64 {
+        self.min
+    }
+
+    pub fn max(&self) -> u64 {
+        self.max
+    }
+
+    pub fn mean(&self) -> f64 {
+        if self.total == 0 {
+            return 0.0;
+        }
+        self.sum as f64 / self.total as f64
+    }
+
+    /// The value below which a fraction q of the recorded values lies, up to the bucket precision.
+    /// 0 for an empty histogram.
+    pub fn value_at_quantile(&self, q: f64) -> u64 {
+        if self.total == 0 {
+            return 0;
+        }
+        let target = ((q.clamp(0.0, 1.0) * self.total as f64).ceil() as u64).max(1);
+        let mut seen = 0;
+        for (bucket, &n) in self.counts.iter().enumerate() {
+            seen += n;
+            if seen >= target {
+                return value_of(bucket).clamp(self.min, self.max);
+            }
+        }
+        self.max
+    }
+
+    /// Adds the values recorded in other, e.g. to combine the histograms of several queues
+    pub fn merge(&mut self, other: &Histogram) {
+        if other.total == 0 {
+            return;
+        }
+        self.min = if self.total == 0 { other.min } else { self.min.min(other.min) };
+        self.max = self.max.max(other.max);
+        self.total += other.total;
+        self.sum = self.sum.wrapping_add(other.sum);
+        for (a, b) in self.counts.iter_mut().zip(other.counts.iter()) {
+            *a += b;
+        }
+    }
+}
+
+impl std::fmt::Debug for Histogram {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Histogram")
+         .field("count", &self.total)
+         .field("min", &self.min)
+         .field("p50", &self.value_at_quantile(0.5))
+         .field("p99", &self.value_at_quantile(0.99))
+         .field("max", &self.max)
+         .finish()
+    }
+}
+
+/// [`Histogram`] shared between the consumers recording into it and monitors reading it.
+/// Several consumers can record into the same one, they briefly contend on its lock.
+#[repr(C, align(64))]
+pub struct LatencyHistogram {
+    fingerprint:     Fingerprint,         // 24
+    removed:         AtomicBool,          // 25
+    // Set by monitors, cleared by the next record
+    reset_requested: AtomicBool,          // 26
+    _pad:            [u8; 38],            // 64
+    histogram:       Seqlock<Histogram>,
+}
+
+impl LatencyHistogram {
+    /// Allocs (unshared) memory for a new histogram, the memory is freed when the handle is dropped
+    pub fn new() -> HistogramHandle {
+        let layout = Layout::new::<Self>();
+        unsafe {
+            let ptr = std::alloc::alloc_zeroed(layout);
+            Handle::heap(Self::from_uninitialized_ptr(ptr), ptr, layout)
+        }
+    }
+
+    pub const fn size_of() -> usize {
+        std::mem::size_of::<Self>()
+    }
+
+    /// Initializes a new histogram in the memory at ptr
+    ///
+    /// # Safety
+    /// ptr has to point to at least [`LatencyHistogram::size_of`] zeroed bytes, 64 byte aligned,
+    /// that stay valid for as long as the histogram is used.
+    pub unsafe fn from_uninitialized_ptr(ptr: *mut u8) -> &'static Self {
+        unsafe {
+            let h = &mut *(ptr as *mut Self);
+            h.fingerprint = Fingerprint::of::<Histogram>();
+            h.removed = AtomicBool::new(false);
+            h.reset_requested = AtomicBool::new(false);
+            h
+        }
+    }
+
+    fn from_initialized_ptr(ptr: *mut u8) -> Result<&'static Self, QueueError> {
+        let h = unsafe { &*(ptr as *const Self) };
+        h.fingerprint.check_type::<Histogram>()?;
+        Ok(h)
+    }
+
+    /// Hot path, doesn't wait for monitors
+    #[inline]
+    pub fn record(&self, latency: u64) {
+        self.histogram.write_multi_with(|h| {
+                                            if self.reset_requested.load(Ordering::Relaxed) {
+                                                h.clear();
+                                                self.reset_requested.store(false, Ordering::Relaxed);
+                                            }
+                                            h.record(latency)
+                                        });
+    }
+
+    /// Records the time passed since stamp, which has to come from clock
+    #[inline]
+    pub fn record_since(&self, clock: Clock, stamp: u64) {
+        self.record(clock.now().saturating_sub(stamp))
+    }
+
+    /// Consistent copy of the histogram, empty if a reset is pending
+    pub fn snapshot(&self) -> Histogram {
+        let mut out = Histogram::default();
+        let reset = self.histogram.read_no_ver_with(|h| {
+                                                        out = *h;
+                                                        self.reset_requested.load(Ordering::Relaxed)
+                                                    });
+        if reset {
+            out.clear();
+        }
+        out
+    }
+
+    /// Requests the histogram to be cleared, which the next record takes care of
+    pub fn reset(&self) {
+        self.reset_requested.store(true, Ordering::Relaxed);
+    }
+
+    /// Whether the shared memory was unlinked
+    pub fn is_removed(&self) -> bool {
+        self.removed.load(Ordering::Acquire)
+    }
+
+    fn mark_removed(&self) {
+        self.removed.store(true, Ordering::Release);
+    }
+}
+
+#[cfg(feature = "shmem")]
+impl LatencyHistogram {
+    /// Creates a histogram in shared memory at shmem_flink, or opens it if it exists already.
+    /// Dropping the handle unmaps the histogram but doesn't remove it.
+    pub fn shared<P: AsRef<std::path::Path>>(shmem_flink: P) -> Result<HistogramHandle, QueueError> {
+        use shared_memory::{ShmemConf, ShmemError};
+        match ShmemConf::new().size(Self::size_of()).flink(&shmem_flink).create() {
+            Ok(mut shmem) => {
+                shmem.set_owner(false);
+                let h = unsafe { Self::from_uninitialized_ptr(shmem.as_ptr()) };
+                Ok(Handle::shmem(h, shmem, Self::mark_removed))
+            }
+            Err(ShmemError::LinkExists) => Self::open_shared(shmem_flink),
+            Err(e) => {
+                eprintln!("Unable to create or open shmem flink {:?} : {e}", shmem_flink.as_ref());
+                Err(e.into())
+            }
+        }
+    }
+
+    pub fn open_shared<P: AsRef<std::path::Path>>(shmem_flink: P) -> Result<HistogramHandle, QueueError> {
+        use shared_memory::ShmemConf;
+        let shmem = ShmemConf::new().flink(&shmem_flink).open()?;
+        let h = Self::from_initialized_ptr(shmem.as_ptr())?;
+        Ok(Handle::shmem(h, shmem, Self::mark_removed))
+    }
+
+    /// Removes the shared memory of the histogram at shmem_flink, handles that still have it open keep working
+    pub fn unlink<P: AsRef<std::path::Path>>(shmem_flink: P) -> Result<(), QueueError> {
+        let mut h = Self::open_shared(shmem_flink)?;
+        h.set_owner(true);
+        Ok(())
+    }
+}
+
+impl std::fmt::Debug for LatencyHistogram {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.snapshot().fmt(f)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn buckets() {
+        for v in [0, 1, 63, 64, 65, 1000, 123_456_789, (1 << MAX_BITS) - 1] {
+            let b = bucket_of(v);
+            assert!(value_of(b) <= v && v - value_of(b) <= v >> SUB_BITS, "{v}");
+            assert!(b < N_BUCKETS);
+        }
+        assert_eq!(bucket_of(u64::MAX), N_BUCKETS - 1);
+        for b in 1..N_BUCKETS {
+            assert!(value_of(b) > value_of(b - 1));
+            assert_eq!(bucket_of(value_of(b)), b);
+        }
+    }
+
+    #[test]
+    fn quantiles() {
+        let mut h = Histogram::default();
+        assert_eq!(h.value_at_quantile(0.5), 0);
+        for v in 1..=1000 {
+            h.record(v);
+        }
+        assert_eq!((h.count(), h.min(), h.max()), (1000, 1, 1000));
+        assert_eq!(h.mean(), 500.5);
+        let p50 = h.value_at_quantile(0.5);
+        assert!((485..=500).contains(&p50), "{p50}");
+        assert_eq!(h.value_at_quantile(1.0), value_of(bucket_of(1000)));
+        assert_eq!(h.value_at_quantile(0.0), 1);
+
+        let mut m = Histogram::default();
+        m.record(5000);
+        m.merge(&h);
+        assert_eq!((m.count(), m.min(), m.max()), (1001, 1, 5000));
+    }
+
+    #[test]
+    fn reset() {
+        let h = LatencyHistogram::new();
+        h.record(10);
+        h.record_since(Clock::Monotonic, Clock::Monotonic.now());
+        assert_eq!(h.snapshot().count(), 2);
+        h.reset();
+        assert_eq!(h.snapshot().count(), 0);
+        h.record(20);
+        let s = h.snapshot();
+        assert_eq!((s.count(), s.min(), s.max()), (1, 20, 20));
+    }
+
+    #[test]
+    #[cfg(feature = "shmem")]
+    fn shared() {
+        let path = std::path::Path::new("/dev/shm/latency_histogram_test");
+        let _ = std::fs::remove_file(path);
+        let h = LatencyHistogram::shared(path).unwrap();
+        let monitor = LatencyHistogram::open_shared(path).unwrap();
+        std::thread::scope(|s| {
+            for _ in 0..2 {
+                s.spawn(|| {
+                     for v in 0..10_000 {
+                         h.record(v);
+                     }
+                 });
+            }
+            let snap = monitor.snapshot();
+            assert!(snap.count() <= 20_000);
+        });
+        assert_eq!(monitor.snapshot().count(), 20_000);
+        LatencyHistogram::unlink(path).unwrap();
+        assert!(h.is_removed());
+    }
+}
@@ -0,0 +1,464 @@
+//! Persisting every message of a queue to disk, in the background.
+//!
+//! A journal is a directory of segments, each a capture in the format of [`crate::record`] named
+//! after the sequence number of its first message, plus two append only files of little endian
+//! u64 pairs: `index` with the first sequence number and timestamp of every segment, and `gaps`
+//! with the first sequence number and length of every range of messages the writer got sped past on.
+use std::{
+    fs::{File, OpenOptions},
+    io::{self, BufWriter, Read, Write},
+    marker::PhantomData,
+    ops::Range,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread::JoinHandle,
+    time::Duration,
+};
+
+use crate::{
+    queue::Consumer,
+    record::{timestamp_of, write_header, write_record, Pod, Recorded, Replayer},
+    ReadError,
+};
+
+const INDEX: &str = "index";
+const GAPS: &str = "gaps";
+
+fn segment_path(dir: &Path, first_seq: u64) -> PathBuf {
+    dir.join(format!("{first_seq:020}.seg"))
+}
+
+fn append(path: PathBuf) -> io::Result<File> {
+    OpenOptions::new().create(true).append(true).open(path)
+}
+
+fn write_pair(f: &mut File, a: u64, b: u64) -> io::Result<()> {
+    let mut buf = [0u8; 16];
+    buf[..8].copy_from_slice(&a.to_le_bytes());
+    buf[8..].copy_from_slice(&b.to_le_bytes());
+    f.write_all(&buf)
+}
+
+// A pair cut off by a crash is ignored
+fn read_pairs(path: PathBuf) -> io::Result<Vec<(u64, u64)>> {
+    let mut bytes = Vec::new();
+    match File::open(path) {
+        Ok(mut f) => f.read_to_end(&mut bytes)?,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e),
+    };
+    Ok(bytes.chunks_exact(16)
+            .map(|c| (u64::from_le_bytes(c[..8].try_into().unwrap()), u64::from_le_bytes(c[8..].try_into().unwrap())))
+            .collect())
+}
+
+// The sequence number of the last message in the journal. The last segments can be empty
+// if a writer stopped right after starting them.
+fn last_seq<T: Pod>(dir: &Path, segments: &[(u64, u64)]) -> io::Result<Option<u64>> {
+    for &(first, _) in segments.iter().rev() {
+        let mut last = None;
+        for r in Replayer::<T>::open(segment_path(dir, first))? {
+            last = Some(r?.seq);
+        }
+        if last.is_some() {
+            return Ok(last);
+        }
+    }
+    Ok(None)
+}
+
+/// Tails a queue through its consumer and appends every message to the journal in dir.
+/// A new segment is started every segment_len messages, and by every writer opening the journal,
+/// so a restarted writer appends to the journal of the previous one.
+pub struct JournalWriter<'a, T> {
+    consumer:     Consumer<'a, T>,
+    dir:          PathBuf,
+    segment_len:  usize,
+    segment:      Option<BufWriter<File>>,
+    in_segment:   usize,
+    index:        File,
+    // First sequence number of the last segment in the index
+    last_indexed: Option<u64>,
+    gaps:         File,
+    written:      usize,
+}
+
+impl<'a, T: Pod> JournalWriter<'a, T> {
+    /// A writer opening an existing journal moves the consumer back to right after the last journaled
+    /// message, so messages produced while no writer was running aren't lost. Those the queue
+    /// doesn't hold anymore are recorded as a gap.
+    pub fn create<P: AsRef<Path>>(mut consumer: Consumer<'a, T>, dir: P, segment_len: usize) -> io::Result<Self> {
+        let dir = dir.as_ref().to_path_buf();
+        std::fs::create_dir_all(&dir)?;
+        let segments = read_pairs(dir.join(INDEX))?;
+        let mut gaps = append(dir.join(GAPS))?;
+        if let Some(last) = last_seq::<T>(&dir, &segments)? {
+            let next = last as usize + 1;
+            // Otherwise the queue started over, e.g. because it was recreated
+            if next <= consumer.queue.count() {
+                let from = next.max(consumer.queue.oldest());
+                if from > next {
+                    write_pair(&mut gaps, next as u64, (from - next) as u64)?;
+                }
+                consumer.set_count(from);
+            }
+        }
+        Ok(Self { consumer,
+                  index: append(dir.join(INDEX))?,
+                  last_indexed: segments.last().map(|&(first, _)| first),
+                  gaps,
+                  dir,
+                  segment_len: segment_len.max(1),
+                  segment: None,
+                  in_segment: 0,
+                  written: 0 })
+    }
+
+    /// Journals all messages that are available right now and flushes them, returning how many
+    pub fn poll(&mut self) -> io::Result<usize> {
+        // Overwritten by every consume, like the buffer of a consumer. All zeros is a valid Pod
+        let mut m: T = unsafe { std::mem::zeroed() };
+        let mut n = 0;
+        loop {
+            match self.consumer.try_consume_stamped(&mut m) {
+                Ok((seq, stamp)) => {
+                    let timestamp = timestamp_of(&self.consumer, stamp);
+                    self.append(seq as u64, timestamp, &m)?;
+                    n += 1;
+                }
+                Err(ReadError::SpedPast) => {
+                    let first = self.consumer.count() as u64;
+                    let lost = self.consumer.recover_after_error();
+                    if lost != 0 {
+                        write_pair(&mut self.gaps, first, lost as u64)?;
+                    }
+                }
+                Err(_) => break,
+            }
+        }
+        if let Some(segment) = &mut self.segment {
+            segment.flush()?;
+        }
+        self.written += n;
+        Ok(n)
+    }
+
+    fn append(&mut self, seq: u64, timestamp: u64, msg: &T) -> io::Result<()> {
+        if self.segment.is_none() || self.in_segment == self.segment_len {
+            self.sync()?;
+            let mut segment = BufWriter::new(self.create_segment(seq)?);
+            write_header::<T, _>(&mut segment)?;
+            // Only indexed once its header is there
+            segment.flush()?;
+            write_pair(&mut self.index, seq, timestamp)?;
+            self.last_indexed = Some(seq);
+            self.segment = Some(segment);
+            self.in_segment = 0;
+        }
+        self.in_segment += 1;
+        write_record(self.segment.as_mut().unwrap(), seq as usize, timestamp, msg)
+    }
+
+    // Never overwrites segments that are in the index
+    fn create_segment(&self, seq: u64) -> io::Result<File> {
+        let path = segment_path(&self.dir, seq);
+        match OpenOptions::new().write(true).create_new(true).open(&path) {
+            // Left behind by a writer that stopped before indexing it, so it holds no messages
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists && !matches!(self.last_indexed, Some(l) if seq <= l) => {
+                File::create(path)
+            }
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {
+                let msg = format!("journal already has a segment starting at {seq}, did the queue start over?");
+                Err(io::Error::new(e.kind(), msg))
+            }
+            r => r,
+        }
+    }
+
+    /// Flushes the current segment and waits for it to reach the disk.
+    /// Done for every full segment, poll only flushes to the OS.
+    pub fn sync(&mut self) -> io::Result<()> {
+        if let Some(segment) = &mut self.segment {
+            segment.flush()?;
+            segment.get_ref().sync_data()?;
+        }
+        Ok(())
+    }
+
+    /// Total number of messages journaled
+    pub fn written(&self) -> usize {
+        self.written
+    }
+}
+
+impl<T: Pod + Send + 'static> JournalWriter<'static, T> {
+    /// Moves the writer to a thread that polls every interval until [`Journaling::stop`]
+    pub fn spawn(self, interval: Duration) -> io::Result<Journaling<T>> {
+        let stop = Arc::new(AtomicBool::new(false));
+        let thread = {
+            let stop = stop.clone();
+            std::thread::Builder::new().name("queue-journal".into())
+                                       .spawn(move || self.run(&stop, interval))?
+        };
+        Ok(Journaling { stop, thread: Some(thread) })
+    }
+
+    fn run(mut self, stop: &AtomicBool, interval: Duration) -> io::Result<Self> {
+        while !stop.load(Ordering::Acquire) {
+            self.poll()?;
+            std::thread::sleep(interval);
+        }
+        self.poll()?;
+        self.sync()?;
+        Ok(self)
+    }
+}
+
+/// A [`JournalWriter`] running in the background, stopped when dropped
+pub struct Journaling<T: 'static> {
+    stop:   Arc<AtomicBool>,
+    thread: Option<JoinHandle<io::Result<JournalWriter<'static, T>>>>,
+}
+
+impl<T: 'static> Journaling<T> {
+    /// Journals what is left in the queue and hands back the writer, or the error that stopped it
+    pub fn stop(mut self) -> io::Result<JournalWriter<'static, T>> {
+        self.stop.store(true, Ordering::Release);
+        match self.thread.take().unwrap().join() {
+            Ok(r) => r,
+            Err(_) => Err(io::Error::other("journal thread panicked")),
+        }
+    }
+
+    /// Whether the writer stopped by itself because of an error
+    pub fn is_finished(&self) -> bool {
+        self.thread.as_ref().is_some_and(JoinHandle::is_finished)
+    }
+}
+
+impl<T: 'static> Drop for Journaling<T> {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Release);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// Reads back the journal in a directory
+pub struct Journal<T> {
+    dir:      PathBuf,
+    // First sequence number and timestamp of every segment
+    segments: Vec<(u64, u64)>,
+    _t:       PhantomData<T>,
+}
+
+impl<T: Pod> Journal<T> {
+    /// Only sees the segments that existed at the time, open again to see newer ones
+    pub fn open<P: AsRef<Path>>(dir: P) -> io::Result<Self> {
+        let dir = dir.as_ref().to_path_buf();
+        let segments = read_pairs(dir.join(INDEX))?;
+        Ok(Self { dir, segments, _t: PhantomData })
+    }
+
+    /// First sequence number and timestamp of every segment
+    pub fn segments(&self) -> &[(u64, u64)] {
+        &self.segments
+    }
+
+    /// The ranges of sequence numbers the writer lost by getting sped past
+    pub fn gaps(&self) -> io::Result<Vec<Range<u64>>> {
+        Ok(read_pairs(self.dir.join(GAPS))?.into_iter().map(|(first, n)| first..first + n).collect())
+    }
+
+    pub fn iter(&self) -> JournalIter<T> {
+        self.by_seq(0..u64::MAX)
+    }
+
+    /// The messages with sequence numbers in seqs
+    pub fn by_seq(&self, seqs: Range<u64>) -> JournalIter<T> {
+        let first = self.segments.partition_point(|&(s, _)| s <= seqs.start).saturating_sub(1);
+        self.iter_segments(first, Filter::Seq(seqs))
+    }
+
+    /// The messages with timestamps in nanos, see [`Recorded::timestamp`].
+    /// Assumes timestamps increase throughout the journal, with several producers stamping
+    /// messages themselves a message near the edges can be missed.
+    pub fn by_time(&self, nanos: Range<u64>) -> JournalIter<T> {
+        let first = self.segments.partition_point(|&(_, t)| t <= nanos.start).saturating_sub(1);
+        self.iter_segments(first, Filter::Time(nanos))
+    }
+
+    fn iter_segments(&self, first: usize, filter: Filter) -> JournalIter<T> {
+        let segments = self.segments
+                           .iter()
+                           .skip(first)
+                           .take_while(|&&(s, t)| match &filter {
+                               Filter::Seq(r) => s < r.end,
+                               Filter::Time(r) => t < r.end,
+                           })
+                           .map(|&(s, _)| segment_path(&self.dir, s))
+                           .collect::<Vec<_>>();
+        JournalIter { segments: segments.into_iter(), current: None, filter }
+    }
+}
+
+enum Filter {
+    Seq(Range<u64>),
+    Time(Range<u64>),
+}
+
+pub struct JournalIter<T> {
+    segments: std::vec::IntoIter<PathBuf>,
+    current:  Option<Replayer<T>>,
+    filter:   Filter,
+}
+
+impl<T: Pod> Iterator for JournalIter<T> {
+    type Item = io::Result<Recorded<T>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let current = match &mut self.current {
+                Some(c) => c,
+                None => match Replayer::open(self.segments.next()?) {
+                    Ok(r) => self.current.insert(r),
+                    Err(e) => return Some(Err(e)),
+                },
+            };
+            match current.next_message() {
+                Ok(Some(r)) => match &self.filter {
+                    Filter::Seq(range) if r.seq >= range.end => {
+                        // Sequence numbers only go up, nothing further can be in range
+                        self.segments = Vec::new().into_iter();
+                        self.current = None;
+                        return None;
+                    }
+                    Filter::Seq(range) if r.seq < range.start => {}
+                    Filter::Time(range) if !range.contains(&r.timestamp) => {}
+                    _ => return Some(Ok(r)),
+                },
+                Ok(None) => self.current = None,
+                Err(e) => {
+                    self.current = None;
+                    return Some(Err(e));
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{clock::Clock, Producer, Queue, QueueType, RecoveryPolicy};
+
+    fn tmp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(name);
+        let _ = std::fs::remove_dir_all(&dir);
+        dir
+    }
+
+    #[test]
+    fn segments_and_gaps() {
+        let dir = tmp_dir("journal_segments_and_gaps");
+        let q = Queue::<u64>::new_with_clock(8, QueueType::SPMC, Clock::Monotonic).unwrap();
+        let mut p = Producer::from(&q);
+        let consumer = Consumer::from(&q).with_recovery(RecoveryPolicy::Oldest);
+        let mut w = JournalWriter::create(consumer, &dir, 4).unwrap();
+        for i in 0..6 {
+            p.produce(&i);
+        }
+        assert_eq!(w.poll().unwrap(), 6);
+        // Sped past, 6..12 are lost and 12..20 survive
+        for i in 6..20 {
+            p.produce(&i);
+        }
+        assert_eq!(w.poll().unwrap(), 8);
+
+        let j = Journal::<u64>::open(&dir).unwrap();
+        assert_eq!(j.segments().iter().map(|s| s.0).collect::<Vec<_>>(), vec![0, 4, 14, 18]);
+        assert_eq!(j.gaps().unwrap(), vec![6..12]);
+        let all = j.iter().map(|r| r.unwrap()).collect::<Vec<_>>();
+        assert_eq!(all.iter().map(|r| r.seq).collect::<Vec<_>>(), (0..6).chain(12..20).collect::<Vec<_>>());
+        assert!(all.iter().all(|r| r.msg == r.seq));
+
+        let seqs = |it: JournalIter<u64>| it.map(|r| r.unwrap().seq).collect::<Vec<_>>();
+        assert_eq!(seqs(j.by_seq(3..14)), vec![3, 4, 5, 12, 13]);
+        assert_eq!(seqs(j.by_seq(15..16)), vec![15]);
+        assert_eq!(seqs(j.by_time(all[2].timestamp..all[5].timestamp)), vec![2, 3, 4]);
+        assert!(Journal::<u32>::open(&dir).unwrap().iter().next().unwrap().is_err());
+
+        // A restarted writer continues in a new segment
+        drop(w);
+        let mut w = JournalWriter::create(Consumer::from(&q), &dir, 4).unwrap();
+        p.produce(&20);
+        assert_eq!(w.poll().unwrap(), 1);
+        let j = Journal::<u64>::open(&dir).unwrap();
+        assert_eq!(j.segments().last().unwrap().0, 20);
+        assert_eq!(j.iter().count(), 15);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn resume() {
+        let dir = tmp_dir("journal_resume");
+        let q = Queue::<u64>::new(8, QueueType::SPMC).unwrap();
+        let mut p = Producer::from(&q);
+        let mut w = JournalWriter::create(Consumer::from(&q), &dir, 4).unwrap();
+        for i in 0..3 {
+            p.produce(&i);
+        }
+        assert_eq!(w.poll().unwrap(), 3);
+        drop(w);
+        // Produced while no writer was running, still in the queue
+        for i in 3..6 {
+            p.produce(&i);
+        }
+        let mut w = JournalWriter::create(Consumer::from(&q), &dir, 4).unwrap();
+        assert_eq!(w.poll().unwrap(), 3);
+        drop(w);
+        // 6..10 get overwritten before the next writer starts
+        for i in 6..18 {
+            p.produce(&i);
+        }
+        let mut w = JournalWriter::create(Consumer::from(&q), &dir, 4).unwrap();
+        assert_eq!(w.poll().unwrap(), 8);
+        let j = Journal::<u64>::open(&dir).unwrap();
+        assert_eq!(j.gaps().unwrap(), vec![6..10]);
+        let seqs = j.iter().map(|r| r.unwrap().seq).collect::<Vec<_>>();
+        assert_eq!(seqs, (0..6).chain(10..18).collect::<Vec<_>>());
+
+        // A queue that started over doesn't overwrite the segments of the old one
+        let q = Queue::<u64>::new(8, QueueType::SPMC).unwrap();
+        let mut w = JournalWriter::create(Consumer::from(&q), &dir, 4).unwrap();
+        Producer::from(&q).produce(&0);
+        assert_eq!(w.poll().unwrap_err().kind(), io::ErrorKind::AlreadyExists);
+        assert_eq!(Journal::<u64>::open(&dir).unwrap().iter().count(), 14);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn background() {
+        let dir = tmp_dir("journal_background");
+        let q = Queue::<u64>::new(1024, QueueType::SPMC).unwrap().leak();
+        let journaling = JournalWriter::create(Consumer::from(q), &dir, 100).unwrap()
+                                                                          .spawn(Duration::from_millis(1))
+                                                                          .unwrap();
+        let mut p = Producer::from(q);
+        for i in 0..500 {
+            p.produce(&i);
+            if i % 50 == 0 {
+                std::thread::sleep(Duration::from_millis(2));
+            }
+        }
+        assert_eq!(journaling.stop().unwrap().written(), 500);
+        let j = Journal::<u64>::open(&dir).unwrap();
+        assert!(j.gaps().unwrap().is_empty());
+        assert_eq!(j.iter().map(|r| r.unwrap().msg).collect::<Vec<_>>(), (0..500).collect::<Vec<_>>());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
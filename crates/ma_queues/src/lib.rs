@@ -1,5 +1,7 @@
 use thiserror::Error;
 
+use fingerprint::Fingerprint;
+
 #[derive(Error, Debug, Copy, Clone, PartialEq)]
 pub enum ReadError {
     #[error("Got sped past")]
@@ -8,6 +10,29 @@ pub enum ReadError {
     Empty,
 }
 
+/// Returned by the blocking reads and consumes that give up at a deadline
+#[derive(Error, Debug, Copy, Clone, PartialEq)]
+#[error("Timed out")]
+pub struct Timeout;
+
+/// Errors of [`ByteConsumer::try_consume_into`]
+#[derive(Error, Debug, Copy, Clone, PartialEq)]
+pub enum ByteReadError {
+    #[error(transparent)]
+    Read(#[from] ReadError),
+    /// Holds the length of the message, which wasn't consumed
+    #[error("Destination buffer too small, message is {0} bytes")]
+    BufferTooSmall(usize),
+}
+
+#[derive(Error, Debug, Copy, Clone, PartialEq)]
+pub enum WriteError {
+    #[error("Queue full")]
+    Full,
+    #[error("Message larger than the queue")]
+    TooLarge,
+}
+
 #[derive(Error, Debug)]
 pub enum QueueError {
     #[error("Queue not initialized")]
@@ -16,6 +41,14 @@ pub enum QueueError {
     LengthNotPowerOfTwo,
     #[error("Element size not power of two - 4")]
     ElementSizeNotPowerTwo,
+    #[error("Cursor name empty or too long")]
+    InvalidCursorName,
+    #[error("No free cursor left in the queue header")]
+    CursorTableFull,
+    #[error("Created with a different layout or element type: expected {expected:?}, found {found:?}")]
+    FingerprintMismatch { expected: Fingerprint, found: Fingerprint },
+    #[error("Existing shmem too small")]
+    TooSmall,
     #[cfg(feature = "shmem")]
     #[error("Shmem error")]
     SharedMemoryError(#[from] shared_memory::ShmemError),
@@ -26,6 +59,35 @@ pub mod ffi;
 pub mod seqlock;
 pub mod vector;
 pub mod queue;
+pub mod byte_queue;
+pub mod cursor;
+pub mod group;
+pub mod fingerprint;
+pub mod handle;
+pub mod heartbeat;
+pub mod clock;
+pub mod histogram;
+pub mod record;
+pub mod journal;
+pub mod wait;
+#[cfg(target_os = "linux")]
+pub mod pollable;
+#[cfg(feature = "async")]
+pub mod async_consumer;
 
-pub use queue::{Queue, Producer, Consumer, QueueType};
+pub use queue::{Queue, Producer, Consumer, Drain, QueueType, RecoveryPolicy, Start};
+pub use byte_queue::{ByteQueue, ByteProducer, ByteConsumer};
+pub use group::GroupConsumer;
 pub use vector::{SeqlockVector};
+pub use fingerprint::TypeHash;
+pub use handle::{Handle, HistogramHandle, QueueHandle, VectorHandle};
+pub use heartbeat::ProducerStatus;
+pub use clock::Clock;
+pub use histogram::{Histogram, LatencyHistogram};
+pub use record::{Pod, Recorded, Recorder, ReplaySpeed, Replayer};
+pub use journal::{Journal, JournalWriter, Journaling};
+pub use wait::{BusySpin, Park, SpinSleep, SpinYield, WaitStrategy};
+#[cfg(target_os = "linux")]
+pub use pollable::PollableConsumer;
+#[cfg(feature = "async")]
+pub use async_consumer::{AsyncConsumer, Notification};
@@ -0,0 +1,204 @@
+//! Consumer for epoll based event loops.
+//!
+//! An eventfd can't be shared through the queue header, so a watcher thread parks on the futex
+//! in the header (see [`crate::wait::Park`]) and signals an eventfd local to the consumer whenever
+//! a producer wakes it up. This also works for queues shared with other processes.
+//!
+//! Like [`crate::wait::Park`], the watcher only registers as a waiter while it sleeps, producers
+//! pay for a futex wake on the messages that find it asleep. Not meant for latency critical paths.
+use std::{
+    io,
+    os::fd::{AsFd, AsRawFd, BorrowedFd, FromRawFd, OwnedFd, RawFd},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread::JoinHandle,
+    time::Duration,
+};
+
+use crate::{
+    handle::QueueHandle,
+    queue::{Consumer, Drain, Queue},
+    ReadError,
+};
+
+/// Bounds how long a wake up missed by the producers, or a stop, can go unnoticed
+const WATCH_TIMEOUT: Duration = Duration::from_millis(10);
+
+/// [`Consumer`] with a file descriptor that becomes readable when new messages are produced,
+/// for use with mio or a plain epoll loop.
+///
+/// Readiness is edge like: once readable, [`PollableConsumer::drain`] (or [`PollableConsumer::clear`]
+/// followed by [`PollableConsumer::try_consume`] until [`ReadError::Empty`]) resets it.
+/// It may become readable without there being new messages.
+pub struct PollableConsumer<T: 'static> {
+    consumer: Consumer<'static, T>,
+    eventfd:  Arc<OwnedFd>,
+    stop:     Arc<AtomicBool>,
+    watcher:  Option<JoinHandle<()>>,
+    // Dropped last, consumer and watcher point into it
+    _handle:  Option<QueueHandle<T>>,
+}
+
+impl<T: Copy + 'static> PollableConsumer<T> {
+    /// Takes over the handle, for heap queues the producers can use [`PollableConsumer::queue`]
+    pub fn new(queue: QueueHandle<T>) -> io::Result<Self> {
+        Self::start(queue.as_static(), Some(queue))
+    }
+
+    pub fn from_static(queue: &'static Queue<T>) -> io::Result<Self> {
+        Self::start(queue, None)
+    }
+
+    fn start(queue: &'static Queue<T>, handle: Option<QueueHandle<T>>) -> io::Result<Self> {
+        let fd = unsafe { libc::eventfd(0, libc::EFD_NONBLOCK | libc::EFD_CLOEXEC) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let eventfd = Arc::new(unsafe { OwnedFd::from_raw_fd(fd) });
+        let stop = Arc::new(AtomicBool::new(false));
+        // Anything produced from here on changes the count the watcher compares against
+        let count = queue.count();
+        // Whatever was there before counts as new
+        signal(&eventfd);
+
+        let watcher = {
+            let (eventfd, stop) = (eventfd.clone(), stop.clone());
+            std::thread::Builder::new().name("queue-watcher".into())
+                                       .spawn(move || watch(queue, &eventfd, &stop, count))?
+        };
+        Ok(Self { consumer: Consumer::from(queue), eventfd, stop, watcher: Some(watcher), _handle: handle })
+    }
+
+    pub fn queue(&self) -> &Queue<T> {
+        self.consumer.queue
+    }
+
+    /// Nonblocking consume returning the sequence number, leaves the readiness as is
+    pub fn try_consume(&mut self, el: &mut T) -> Result<usize, ReadError> {
+        self.consumer.try_consume(el)
+    }
+
+    /// Nonblocking consume running f on the message in place, see [`Consumer::try_consume_with`]
+    pub fn try_consume_with<R, F: FnOnce(&T) -> R>(&mut self, f: F) -> Result<R, ReadError> {
+        self.consumer.try_consume_with(f)
+    }
+
+    /// Resets the readiness and then iterates over the available messages, see [`Consumer::drain`]
+    pub fn drain(&mut self) -> Drain<'_, 'static, T> {
+        self.clear();
+        self.consumer.drain()
+    }
+
+    pub fn recover_after_error(&mut self) -> usize {
+        self.consumer.recover_after_error()
+    }
+
+    /// The count of the next message this consumer will read
+    pub fn count(&self) -> usize {
+        self.consumer.count()
+    }
+
+    /// Resets the readiness of the file descriptor
+    pub fn clear(&self) {
+        let mut buf = 0u64;
+        unsafe { libc::read(self.eventfd.as_raw_fd(), &mut buf as *mut u64 as *mut libc::c_void, 8) };
+    }
+}
+
+impl<T> AsRawFd for PollableConsumer<T> {
+    fn as_raw_fd(&self) -> RawFd {
+        self.eventfd.as_raw_fd()
+    }
+}
+
+impl<T> AsFd for PollableConsumer<T> {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.eventfd.as_fd()
+    }
+}
+
+impl<T: 'static> Drop for PollableConsumer<T> {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Release);
+        self.consumer.queue.header.notifier().wake_all();
+        if let Some(watcher) = self.watcher.take() {
+            let _ = watcher.join();
+        }
+    }
+}
+
+// Signals whenever the count changed, sleeping in between
+fn watch<T: Copy>(queue: &Queue<T>, eventfd: &OwnedFd, stop: &AtomicBool, mut count: usize) {
+    let notifier = queue.header.notifier();
+    while !stop.load(Ordering::Acquire) {
+        let seq = notifier.register();
+        // The count is checked once more after registering, so a message produced
+        // in between doesn't leave us sleeping
+        if queue.count() == count {
+            notifier.wait(seq, WATCH_TIMEOUT);
+        }
+        notifier.unregister();
+        let c = queue.count();
+        if c != count {
+            signal(eventfd);
+            count = c;
+        }
+    }
+}
+
+fn signal(eventfd: &OwnedFd) {
+    let one = 1u64;
+    unsafe { libc::write(eventfd.as_raw_fd(), &one as *const u64 as *const libc::c_void, 8) };
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{Producer, QueueType};
+
+    fn readable(fd: RawFd, timeout_ms: i32) -> bool {
+        let mut pfd = libc::pollfd { fd, events: libc::POLLIN, revents: 0 };
+        unsafe { libc::poll(&mut pfd, 1, timeout_ms) == 1 }
+    }
+
+    #[test]
+    fn readiness() {
+        let q = Queue::<usize>::new(16, QueueType::SPMC).unwrap().leak();
+        let mut p = Producer::from(q);
+        p.produce(&0);
+        let mut c = PollableConsumer::from_static(q).unwrap();
+        assert!(readable(c.as_raw_fd(), 0));
+        assert_eq!(c.drain().count(), 0);
+        assert!(!readable(c.as_raw_fd(), 0));
+
+        p.produce(&1);
+        assert!(readable(c.as_raw_fd(), 1000));
+        assert_eq!(c.drain().map(Result::unwrap).collect::<Vec<_>>(), vec![1]);
+        drop(c);
+        assert_eq!(q.header.notifier().waiters(), 0);
+    }
+
+    #[test]
+    #[cfg(feature = "shmem")]
+    fn readiness_shared() {
+        let path = std::path::Path::new("/dev/shm/pollable_readiness_shared");
+        let _ = std::fs::remove_file(path);
+        let q = Queue::<usize>::shared(path, 16, QueueType::SPMC).unwrap();
+        let mut c = PollableConsumer::new(Queue::<usize>::open_shared(path).unwrap()).unwrap();
+        c.clear();
+        std::thread::scope(|s| {
+            s.spawn(|| {
+                 std::thread::sleep(Duration::from_millis(10));
+                 Producer::from(&q).produce(&42);
+             });
+            assert!(readable(c.as_raw_fd(), 5000));
+        });
+        let mut m = 0;
+        c.try_consume(&mut m).unwrap();
+        assert_eq!(m, 42);
+        drop(c);
+        Queue::<usize>::unlink(path).unwrap();
+    }
+}
@@ -1,10 +1,24 @@
 use std::{
     alloc::Layout,
-    mem::size_of,
-    sync::atomic::{AtomicUsize, Ordering},
+    convert::Infallible,
+    mem::{size_of, MaybeUninit},
+    sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+    time::{Duration, Instant},
 };
 
-use crate::{seqlock::Seqlock, QueueError, ReadError};
+use crate::{
+    clock::Clock,
+    cursor::{self, Cursor, MAX_CURSORS},
+    fingerprint::{Fingerprint, TypeHash},
+    handle::{Handle, QueueHandle},
+    heartbeat::{Heartbeat, ProducerStatus},
+    seqlock::Seqlock,
+    wait::{pause, BusySpin, Notifier, WaitStrategy},
+    QueueError, ReadError, Timeout, WriteError,
+};
+
+/// Producers stamp the heartbeat every this many messages, see [`Producer::heartbeat`]
+pub const HEARTBEAT_EVERY: usize = 1024;
 
 #[derive(Debug, Clone, Copy)]
 #[repr(u8)]
@@ -12,17 +26,34 @@ pub enum QueueType {
     Unknown,
     MPMC,
     SPMC,
+    /// Lossless: the consumer publishes its position and the producer
+    /// gets [`WriteError::Full`] instead of overwriting unread messages.
+    SPSC,
+    /// Lossless multi producer version of [`QueueType::SPSC`].
+    MPSC,
 }
 
 #[derive(Debug)]
 #[repr(C, align(64))]
 pub struct QueueHeader {
-    queue_type:     QueueType,   // 1
-    is_initialized: u8,          // 2
-    _pad1:          [u8; 6],     // 8
-    elsize:         usize,       // 16
-    mask:           usize,       // 24
-    count:          AtomicUsize, // 32
+    queue_type:     QueueType,             // 1
+    is_initialized: u8,                    // 2
+    removed:        AtomicBool,            // 3 set right before the shared memory gets unlinked
+    clock:          Clock,                 // 4 what producers stamp messages with, if anything
+    _pad1:          [u8; 4],               // 8
+    elsize:         usize,                 // 16
+    mask:           usize,                 // 24
+    count:          AtomicUsize,           // 32
+    fingerprint:    Fingerprint,           // 56
+    // Next to count, which producers write anyway
+    notifier:       Notifier,              // 64
+    // Only used by lossless queues, written by the consumer. Off the cacheline of count,
+    // it shares its own with the heartbeat, which producers only write every HEARTBEAT_EVERY messages.
+    read_count:     AtomicUsize,           // 72
+    heartbeat:      Heartbeat,             // 88
+    _pad3:          [u8; 40],              // 128
+    // Named read positions, e.g. of consumer groups
+    cursors:        [Cursor; MAX_CURSORS], // 640
 }
 impl QueueHeader {
     /// in bytes
@@ -46,9 +77,34 @@ impl QueueHeader {
         self.is_initialized == 1
     }
 
+    /// Whether the shared memory was unlinked, no new messages will be produced into it
+    pub fn is_removed(&self) -> bool {
+        self.removed.load(Ordering::Acquire)
+    }
+
     pub fn elsize(&self) -> usize {
         self.elsize
     }
+
+    pub fn clock(&self) -> Clock {
+        self.clock
+    }
+
+    pub fn fingerprint(&self) -> &Fingerprint {
+        &self.fingerprint
+    }
+
+    pub fn notifier(&self) -> &Notifier {
+        &self.notifier
+    }
+
+    pub fn heartbeat(&self) -> &Heartbeat {
+        &self.heartbeat
+    }
+
+    pub fn cursors(&self) -> &[Cursor] {
+        &self.cursors
+    }
 }
 
 #[cfg(feature = "shmem")]
@@ -75,23 +131,37 @@ fn power_of_two(mut v: usize) -> usize {
     n
 }
 
+/// With a [`Clock`] the buffer is followed by one timestamp per slot.
 #[repr(C, align(64))]
 pub struct Queue<T> {
     pub header: QueueHeader,
     buffer:     [Seqlock<T>],
 }
 
-impl<T: Copy> Queue<T> {
-    /// Allocs (unshared) memory and initializes a new queue from it
-    pub fn new(len: usize, queue_type: QueueType) -> Result<&'static Self, QueueError> {
+impl<T: Copy + TypeHash> Queue<T> {
+    /// Allocs (unshared) memory and initializes a new queue from it, the memory is freed when
+    /// the handle is dropped
+    pub fn new(len: usize, queue_type: QueueType) -> Result<QueueHandle<T>, QueueError> {
+        Self::new_with_clock(len, queue_type, Clock::None)
+    }
+
+    /// [`Queue::new`] where producers stamp every message with clock, see [`Consumer::try_consume_stamped`]
+    pub fn new_with_clock(len: usize, queue_type: QueueType, clock: Clock) -> Result<QueueHandle<T>, QueueError> {
         let real_len = len.next_power_of_two();
-        let size = size_of::<QueueHeader>() + real_len * size_of::<Seqlock<T>>();
+        let size = Self::size_of_with_clock(real_len, clock);
+        let layout = Layout::array::<u8>(size).unwrap().align_to(64).unwrap().pad_to_align();
 
         unsafe {
-            let ptr = std::alloc::alloc_zeroed(Layout::array::<u8>(size).unwrap().align_to(64).unwrap().pad_to_align());
+            let ptr = std::alloc::alloc_zeroed(layout);
             // Why real len you may ask. The size of the fat pointer ONLY includes the length of the
             // unsized part of the struct i.e. the buffer.
-            Self::from_uninitialized_ptr(ptr, real_len, queue_type)
+            match Self::from_uninitialized_ptr_with_clock(ptr, real_len, queue_type, clock) {
+                Ok(q) => Ok(Handle::heap(q, ptr, layout)),
+                Err(e) => {
+                    std::alloc::dealloc(ptr, layout);
+                    Err(e)
+                }
+            }
         }
     }
 
@@ -99,10 +169,35 @@ impl<T: Copy> Queue<T> {
         size_of::<QueueHeader>() + len.next_power_of_two() * size_of::<Seqlock<T>>()
     }
 
-    pub fn from_uninitialized_ptr(ptr: *mut u8,
-                                  len: usize,
-                                  queue_type: QueueType)
-                                  -> Result<&'static Self, QueueError> {
+    pub const fn size_of_with_clock(len: usize, clock: Clock) -> usize {
+        match clock {
+            Clock::None => Self::size_of(len),
+            _ => Self::size_of(len) + len.next_power_of_two() * size_of::<AtomicU64>(),
+        }
+    }
+
+    /// Initializes a new queue in the memory at ptr
+    ///
+    /// # Safety
+    /// ptr has to point to at least [`Queue::size_of`] bytes, 64 byte aligned, that stay valid
+    /// for as long as the queue is used.
+    pub unsafe fn from_uninitialized_ptr(ptr: *mut u8,
+                                         len: usize,
+                                         queue_type: QueueType)
+                                         -> Result<&'static Self, QueueError> {
+        unsafe { Self::from_uninitialized_ptr_with_clock(ptr, len, queue_type, Clock::None) }
+    }
+
+    /// Initializes a new queue with timestamps in the memory at ptr
+    ///
+    /// # Safety
+    /// ptr has to point to at least [`Queue::size_of_with_clock`] bytes, 64 byte aligned, that stay
+    /// valid for as long as the queue is used.
+    pub unsafe fn from_uninitialized_ptr_with_clock(ptr: *mut u8,
+                                                    len: usize,
+                                                    queue_type: QueueType,
+                                                    clock: Clock)
+                                                    -> Result<&'static Self, QueueError> {
         if !len.is_power_of_two() {
             return Err(QueueError::LengthNotPowerOfTwo);
         }
@@ -116,17 +211,30 @@ impl<T: Copy> Queue<T> {
 
             let mask = len - 1;
 
+            q.header.fingerprint = Fingerprint::of::<T>();
             q.header.queue_type = queue_type;
+            q.header.clock = clock;
             q.header.mask = mask;
             q.header.elsize = elsize;
+            q.header.removed = AtomicBool::new(false);
             q.header.is_initialized = true as u8;
             q.header.count = AtomicUsize::new(0);
+            q.header.read_count = AtomicUsize::new(0);
+            q.header.notifier = Notifier::default();
+            q.header.heartbeat = Heartbeat::default();
+            for cursor in q.header.cursors.iter_mut() {
+                cursor.reset();
+            }
             Ok(q)
         }
     }
 
-    #[allow(dead_code)]
-    pub fn from_initialized_ptr(ptr: *mut QueueHeader) -> Result<&'static Self, QueueError> {
+    /// Opens the queue that was initialized at ptr
+    ///
+    /// # Safety
+    /// ptr has to point to a queue header followed by its buffer, that stay valid for as long as
+    /// the queue is used.
+    pub unsafe fn from_initialized_ptr(ptr: *mut QueueHeader) -> Result<&'static Self, QueueError> {
         unsafe {
             let len = (*ptr).mask + 1;
             if !len.is_power_of_two() {
@@ -135,61 +243,266 @@ impl<T: Copy> Queue<T> {
             if (*ptr).is_initialized != true as u8 {
                 return Err(QueueError::UnInitialized);
             }
+            (*ptr).fingerprint.check_type::<T>()?;
 
             Ok(&*(std::ptr::slice_from_raw_parts_mut(ptr, len) as *const Queue<T>))
         }
     }
+}
 
+impl<T: Copy> Queue<T> {
     // Note: Calling this from anywhere that's not a producer -> false sharing
     pub fn count(&self) -> usize {
         self.header.count.load(Ordering::Relaxed)
     }
 
-    fn next_count(&self) -> usize {
-        match self.header.queue_type {
+    // Claims n consecutive counts, returning the first one
+    pub(crate) fn next_count(&self, n: usize) -> usize {
+        let c = match self.header.queue_type {
             QueueType::Unknown => panic!("Unknown queue"),
-            QueueType::MPMC => self.header.count.fetch_add(1, Ordering::AcqRel),
-            QueueType::SPMC => {
+            QueueType::MPMC | QueueType::MPSC => self.header.count.fetch_add(n, Ordering::AcqRel),
+            QueueType::SPMC | QueueType::SPSC => {
                 let c = self.header.count.load(Ordering::Relaxed);
-                self.header.count.store(c.wrapping_add(1), Ordering::Relaxed);
+                self.header.count.store(c.wrapping_add(n), Ordering::Relaxed);
                 c
             }
+        };
+        self.claimed(c, n)
+    }
+
+    // Stamps the heartbeat when the claimed counts cross a multiple of HEARTBEAT_EVERY
+    fn claimed(&self, c: usize, n: usize) -> usize {
+        let last = c.wrapping_add(n).wrapping_sub(1);
+        if n != 0 && c.wrapping_sub(1) / HEARTBEAT_EVERY != last / HEARTBEAT_EVERY {
+            self.header.heartbeat.beat();
+        }
+        c
+    }
+
+    pub(crate) fn is_lossless(&self) -> bool {
+        matches!(self.header.queue_type, QueueType::SPSC | QueueType::MPSC)
+    }
+
+    // Only claims the next n counts if the consumer has read the messages currently in those slots.
+    // read_count is only reloaded from the header when the cached value says the queue is full.
+    pub(crate) fn try_next_count(&self, cached_read_count: &mut usize, n: usize) -> Result<usize, WriteError> {
+        if n > self.len() {
+            return Err(WriteError::TooLarge);
+        }
+        let max_used = self.len() - n;
+        loop {
+            let c = self.header.count.load(Ordering::Acquire);
+            if c.wrapping_sub(*cached_read_count) > max_used {
+                *cached_read_count = self.header.read_count.load(Ordering::Acquire);
+                let used = c.wrapping_sub(*cached_read_count);
+                // The consumer already read past c, i.e. other producers claimed more in the meantime
+                let fresh = self.header.count.load(Ordering::Acquire);
+                if used > self.len() && fresh.wrapping_sub(*cached_read_count) <= self.len() {
+                    continue;
+                }
+                if used > max_used {
+                    return Err(WriteError::Full);
+                }
+            }
+            match self.header.queue_type {
+                // Other producers may have claimed c in the meantime, in which case we check again
+                QueueType::MPSC => {
+                    if self.header
+                           .count
+                           .compare_exchange_weak(c, c.wrapping_add(n), Ordering::AcqRel, Ordering::Relaxed)
+                           .is_ok()
+                    {
+                        return Ok(self.claimed(c, n));
+                    }
+                }
+                _ => return Ok(self.next_count(n)),
+            }
+        }
+    }
+
+    // The oldest message still in the queue, i.e. the first one whose slot wasn't overwritten yet.
+    // For lossless queues the oldest unread one.
+    pub(crate) fn oldest(&self) -> usize {
+        self.oldest_at(self.count())
+    }
+
+    /// Oldest message still readable relative to a count loaded earlier, never past it.
+    pub(crate) fn oldest_at(&self, newest: usize) -> usize {
+        if self.is_lossless() {
+            return self.read_count().min(newest);
+        }
+        let mut c = newest.saturating_sub(self.len());
+        while c < newest && self.version_of(c & self.header.mask) != self.version_for(c) {
+            c += 1;
         }
+        c
     }
 
-    fn load(&self, pos: usize) -> &Seqlock<T> {
+    pub(crate) fn read_count(&self) -> usize {
+        self.header.read_count.load(Ordering::Acquire)
+    }
+
+    // The consume path of a lossless queue, which has a single reader
+    pub(crate) fn store_read_count(&self, c: usize) {
+        self.header.read_count.store(c, Ordering::Release);
+    }
+
+    // For when several readers share the read position, the furthest one wins
+    pub(crate) fn advance_read_count(&self, c: usize) {
+        self.header.read_count.fetch_max(c, Ordering::Release);
+    }
+
+    pub(crate) fn load(&self, pos: usize) -> &Seqlock<T> {
         unsafe { self.buffer.get_unchecked(pos) }
     }
 
-    fn cur_pos(&self) -> usize {
-        self.count() & self.header.mask
+    // The version a slot will have once the message with count c is written into it
+    pub(crate) fn version_for(&self, c: usize) -> usize {
+        ((c / (self.header.mask + 1)) << 1) + 2
     }
 
-    fn version(&self) -> usize {
-        ((self.count() / (self.header.mask + 1)) << 1) + 2
+    pub fn is_removed(&self) -> bool {
+        self.header.is_removed()
     }
 
     pub fn version_of(&self, pos: usize) -> usize {
         self.load(pos).version()
     }
 
+    // The timestamps following the buffer, only exist with a clock
+    fn stamps(&self) -> &[AtomicU64] {
+        debug_assert!(self.header.clock != Clock::None);
+        unsafe {
+            let ptr = self.buffer.as_ptr().add(self.len()) as *const AtomicU64;
+            std::slice::from_raw_parts(ptr, self.len())
+        }
+    }
+
+    // Only called while the version of the slot is odd, so consumers see the stamp that belongs to the message
+    #[inline(always)]
+    fn set_stamp(&self, c: usize, stamp: u64) {
+        if self.header.clock != Clock::None {
+            self.stamps()[c & self.header.mask].store(stamp, Ordering::Relaxed);
+        }
+    }
+
+    #[inline(always)]
+    fn write(&self, c: usize, item: &T) {
+        match self.header.clock {
+            Clock::None => self.load(c & self.header.mask).write(item),
+            _ => self.write_with(c, |slot| {
+                          slot.write(*item);
+                      }),
+        }
+    }
+
+    #[inline(always)]
+    pub(crate) fn write_with<F: FnOnce(&mut MaybeUninit<T>)>(&self, c: usize, f: F) {
+        let stamp = self.header.clock.now();
+        self.load(c & self.header.mask).write_with(|slot| {
+                                            self.set_stamp(c, stamp);
+                                            f(slot)
+                                        });
+    }
+
+    // For the first writes of a single producer, whose predecessor may have died mid-write
+    // and left the slot locked
+    pub(crate) fn write_first_with<F: FnOnce(&mut MaybeUninit<T>)>(&self, c: usize, f: F) {
+        let lock = self.load(c & self.header.mask);
+        let single = matches!(self.header.queue_type, QueueType::SPMC | QueueType::SPSC);
+        if single && lock.version() & 1 == 1 {
+            let stamp = self.header.clock.now();
+            lock.write_unpoison_with(|slot| {
+                    self.set_stamp(c, stamp);
+                    f(slot)
+                });
+        } else {
+            self.write_with(c, f)
+        }
+    }
+
     // returns the current count
     fn produce(&self, item: &T) -> usize {
-        let p = self.next_count();
-        let lock = self.load(p & self.header.mask);
-        lock.write(item);
+        let p = self.next_count(1);
+        self.write(p, item);
+        self.notify();
+        p
+    }
+
+    fn produce_with<F: FnOnce(&mut MaybeUninit<T>)>(&self, f: F) -> usize {
+        let p = self.next_count(1);
+        self.write_with(p, f);
+        self.notify();
+        p
+    }
+
+    fn try_produce_with<F>(&self, f: F, cached_read_count: &mut usize) -> Result<usize, WriteError>
+        where F: FnOnce(&mut MaybeUninit<T>)
+    {
+        let p = self.try_next_count(cached_read_count, 1)?;
+        self.write_with(p, f);
+        self.notify();
+        Ok(p)
+    }
+
+    fn try_produce(&self, item: &T, cached_read_count: &mut usize) -> Result<usize, WriteError> {
+        let p = self.try_next_count(cached_read_count, 1)?;
+        self.write(p, item);
+        self.notify();
+        Ok(p)
+    }
+
+    // Claims the counts for n items in one go, returns the count of the first one
+    fn produce_n(&self, items: &[T]) -> usize {
+        let p = self.next_count(items.len());
+        self.write_n(p, items);
         p
     }
 
-    fn consume(&self, el: &mut T, ri: usize, ri_ver: usize) -> Result<(), ReadError> {
+    fn try_produce_n(&self, items: &[T], cached_read_count: &mut usize) -> Result<usize, WriteError> {
+        let p = self.try_next_count(cached_read_count, items.len())?;
+        self.write_n(p, items);
+        Ok(p)
+    }
+
+    fn write_n(&self, p: usize, items: &[T]) {
+        for (i, item) in items.iter().enumerate() {
+            self.write(p.wrapping_add(i), item);
+        }
+        self.notify();
+    }
+
+    // Wakes up parked consumers, if any
+    #[inline(always)]
+    pub(crate) fn notify(&self) {
+        self.header.notifier.notify();
+    }
+
+    pub(crate) fn consume(&self, el: &mut T, ri: usize, ri_ver: usize) -> Result<(), ReadError> {
         self.load(ri).read(el, ri_ver)
     }
 
+    // Also returns the timestamp of the message, 0 without a clock
+    pub(crate) fn consume_stamped(&self, el: &mut T, ri: usize, ri_ver: usize) -> Result<u64, ReadError> {
+        if self.header.clock == Clock::None {
+            return self.consume(el, ri, ri_ver).map(|_| 0);
+        }
+        self.load(ri).read_with(|m| {
+                              *el = *m;
+                              self.stamps()[ri].load(Ordering::Relaxed)
+                          },
+                          ri_ver)
+    }
+
+    pub(crate) fn consume_with<R, F: FnOnce(&T) -> R>(&self, f: F, ri: usize, ri_ver: usize) -> Result<R, ReadError> {
+        self.load(ri).read_with(f, ri_ver)
+    }
+
     pub fn read(&self, el: &mut T, ri: usize) {
         self.load(ri).read_no_ver(el)
     }
 
-    fn len(&self) -> usize {
+    pub(crate) fn len(&self) -> usize {
         self.header.mask + 1
     }
 
@@ -218,21 +531,50 @@ impl<T: Copy> Queue<T> {
     fn produce_first(&self, item: &T) -> usize {
         match self.header.queue_type {
             QueueType::Unknown => panic!("Unknown queue"),
-            QueueType::MPMC => self.produce(item),
-            QueueType::SPMC => {
+            QueueType::MPMC | QueueType::MPSC => self.produce(item),
+            QueueType::SPMC | QueueType::SPSC => {
                 let m = self.header.mask;
                 let c = self.count();
                 let p = c & m;
                 let lock = self.load(p);
                 if lock.version() & 1 == 1 {
-                    lock.write_unpoison(item);
-                    p
+                    let stamp = self.header.clock.now();
+                    lock.write_unpoison_with(|slot| {
+                            self.set_stamp(c, stamp);
+                            slot.write(*item);
+                        });
+                    self.notify();
+                    c
                 } else {
                     self.produce(item)
                 }
             }
         }
     }
+
+    fn produce_first_with<F: FnOnce(&mut MaybeUninit<T>)>(&self, f: F) -> usize {
+        match self.header.queue_type {
+            QueueType::Unknown => panic!("Unknown queue"),
+            QueueType::MPMC | QueueType::MPSC => self.produce_with(f),
+            QueueType::SPMC | QueueType::SPSC => {
+                let m = self.header.mask;
+                let c = self.count();
+                let p = c & m;
+                let lock = self.load(p);
+                if lock.version() & 1 == 1 {
+                    let stamp = self.header.clock.now();
+                    lock.write_unpoison_with(|slot| {
+                            self.set_stamp(c, stamp);
+                            f(slot)
+                        });
+                    self.notify();
+                    c
+                } else {
+                    self.produce_with(f)
+                }
+            }
+        }
+    }
 }
 
 unsafe impl<T> Send for Queue<T> {}
@@ -245,24 +587,31 @@ impl<T: std::fmt::Debug> std::fmt::Debug for Queue<T> {
 }
 
 #[cfg(feature = "shmem")]
-impl<T: Copy> Queue<T> {
+impl<T: Copy + TypeHash> Queue<T> {
+    /// Creates a queue in shared memory at shmem_flink, or opens it if it exists already.
+    /// Dropping the handle unmaps the queue but doesn't remove it.
     pub fn shared<P: AsRef<std::path::Path>>(shmem_flink: P,
                                              size: usize,
                                              typ: QueueType)
-                                             -> Result<&'static Self, QueueError> {
+                                             -> Result<QueueHandle<T>, QueueError> {
+        Self::shared_with_clock(shmem_flink, size, typ, Clock::None)
+    }
+
+    /// [`Queue::shared`] where producers stamp every message with clock.
+    /// An existing queue is opened with whatever clock it was created with.
+    pub fn shared_with_clock<P: AsRef<std::path::Path>>(shmem_flink: P,
+                                                        size: usize,
+                                                        typ: QueueType,
+                                                        clock: Clock)
+                                                        -> Result<QueueHandle<T>, QueueError> {
         use shared_memory::{ShmemConf, ShmemError};
-        match ShmemConf::new().size(Self::size_of(size)).flink(&shmem_flink).create() {
-            Ok(shmem) => {
-                let ptr = shmem.as_ptr();
-                std::mem::forget(shmem);
-                Self::from_uninitialized_ptr(ptr, size, typ)
-            }
-            Err(ShmemError::LinkExists) => {
-                let shmem = ShmemConf::new().flink(shmem_flink).open().unwrap();
-                let ptr = shmem.as_ptr() as *mut QueueHeader;
-                std::mem::forget(shmem);
-                Self::from_initialized_ptr(ptr)
+        match ShmemConf::new().size(Self::size_of_with_clock(size, clock)).flink(&shmem_flink).create() {
+            Ok(mut shmem) => {
+                shmem.set_owner(false);
+                let q = unsafe { Self::from_uninitialized_ptr_with_clock(shmem.as_ptr(), size, typ, clock)? };
+                Ok(Handle::shmem(q, shmem, Self::mark_removed))
             }
+            Err(ShmemError::LinkExists) => Self::open_shared(shmem_flink),
             Err(e) => {
                 eprintln!("Unable to create or open shmem flink {:?} : {e}", shmem_flink.as_ref());
                 Err(e.into())
@@ -270,13 +619,12 @@ impl<T: Copy> Queue<T> {
         }
     }
 
-    pub fn open_shared<P: AsRef<std::path::Path>>(shmem_flink: P) -> Result<&'static Self, QueueError> {
+    pub fn open_shared<P: AsRef<std::path::Path>>(shmem_flink: P) -> Result<QueueHandle<T>, QueueError> {
         use shared_memory::ShmemConf;
         match ShmemConf::new().flink(&shmem_flink).open() {
             Ok(shmem) => {
-                let ptr = shmem.as_ptr() as *mut QueueHeader;
-                std::mem::forget(shmem);
-                unsafe { Self::shared(shmem_flink, (*ptr).len(), (*ptr).queue_type) }
+                let q = unsafe { Self::from_initialized_ptr(shmem.as_ptr() as *mut QueueHeader)? };
+                Ok(Handle::shmem(q, shmem, Self::mark_removed))
             }
             Err(e) => {
                 eprintln!("Unable to create or open shmem flink {:?} : {e}", shmem_flink.as_ref());
@@ -284,24 +632,61 @@ impl<T: Copy> Queue<T> {
             }
         }
     }
+
+    /// Removes the shared memory of the queue at shmem_flink. Producers and consumers that
+    /// still have it open keep working, and can find out through [`Queue::is_removed`].
+    /// Works regardless of the element type or layout the queue was created with.
+    pub fn unlink<P: AsRef<std::path::Path>>(shmem_flink: P) -> Result<(), QueueError> {
+        use shared_memory::ShmemConf;
+        let mut shmem = ShmemConf::new().flink(&shmem_flink).open()?;
+        // Where removed lives is only known for our current layout
+        if shmem.len() >= std::mem::size_of::<QueueHeader>() {
+            let header = QueueHeader::from_ptr(shmem.as_ptr());
+            if header.fingerprint.check_layout().is_ok() {
+                header.removed.store(true, Ordering::Release);
+            }
+        }
+        shmem.set_owner(true);
+        Ok(())
+    }
+
+    fn mark_removed(&self) {
+        self.header.removed.store(true, Ordering::Release);
+    }
 }
 
 /// Simply exists for the automatic produce_first
 #[repr(C, align(64))]
 pub struct Producer<'a, T> {
     // can't we just make this a usize since we're anyway padding?
-    pub produced_first: u8, // 1
-    pub queue:          &'a Queue<T>,
+    pub produced_first: u8,           // 1
+    pub queue:          &'a Queue<T>, // 24
+    pub(crate) read_count: usize,     // 32 last seen consumer position, lossless queues only
 }
 
 impl<'a, T: Copy> From<&'a Queue<T>> for Producer<'a, T> {
     fn from(queue: &'a Queue<T>) -> Self {
-        Self { produced_first: 0, queue }
+        Self { produced_first: 0, queue, read_count: 0 }
     }
 }
 
 impl<'a, T: Copy> Producer<'a, T> {
+    /// Nonblocking produce, returning the sequence number of msg. Only lossless queues ([`QueueType::SPSC`] and [`QueueType::MPSC`]) can return
+    /// [`WriteError::Full`], the others always overwrite the oldest message.
+    pub fn try_produce(&mut self, msg: &T) -> Result<usize, WriteError> {
+        if !self.queue.is_lossless() {
+            return Ok(self.produce(msg));
+        }
+        self.queue.try_produce(msg, &mut self.read_count)
+    }
+
+    /// Produces msg and returns its sequence number, the producers' count it was written at.
+    /// Consumers get the same number back from [`Consumer::try_consume`].
+    /// On lossless queues this blocks until the consumer has freed up a slot.
     pub fn produce(&mut self, msg: &T) -> usize {
+        if self.queue.is_lossless() {
+            return self.produce_lossless(|q, read_count| q.try_produce(msg, read_count));
+        }
         if self.produced_first == 0 {
             self.produced_first = 1;
             self.queue.produce_first(msg)
@@ -309,6 +694,114 @@ impl<'a, T: Copy> Producer<'a, T> {
             self.queue.produce(msg)
         }
     }
+
+    /// Produces a message by letting f write it directly into the slot, saving a copy for large messages.
+    /// The slot still holds an older message, so f has to fully initialize it.
+    /// On lossless queues this blocks until the consumer has freed up a slot.
+    ///
+    /// f must not panic, that leaves the slot locked and consumers stuck on it, like a producer
+    /// that dies mid write.
+    pub fn produce_with<F: FnOnce(&mut MaybeUninit<T>)>(&mut self, f: F) -> usize {
+        if self.queue.is_lossless() {
+            let mut f = Some(f);
+            return self.produce_lossless(|q, read_count| {
+                           q.try_produce_with(|slot| f.take().unwrap()(slot), read_count)
+                       });
+        }
+        if self.produced_first == 0 {
+            self.produced_first = 1;
+            self.queue.produce_first_with(f)
+        } else {
+            self.queue.produce_with(f)
+        }
+    }
+
+    /// Nonblocking [`Producer::produce_with`], f is only called if there is room in the queue.
+    pub fn try_produce_with<F: FnOnce(&mut MaybeUninit<T>)>(&mut self, f: F) -> Result<usize, WriteError> {
+        if !self.queue.is_lossless() {
+            return Ok(self.produce_with(f));
+        }
+        self.queue.try_produce_with(f, &mut self.read_count)
+    }
+
+    /// Produces all msgs, claiming their counts in one go instead of one by one.
+    /// Returns the count of the first message.
+    ///
+    /// On lossless queues this blocks until there is room for the whole batch. Batches longer than
+    /// the queue are split up, so on [`QueueType::MPSC`] messages of other producers may end up in between.
+    pub fn produce_batch(&mut self, msgs: &[T]) -> usize {
+        if self.queue.is_lossless() {
+            let mut first = None;
+            for chunk in msgs.chunks(self.queue.len()) {
+                let c = self.produce_lossless(|q, read_count| q.try_produce_n(chunk, read_count));
+                first.get_or_insert(c);
+            }
+            return first.unwrap_or_else(|| self.queue.count());
+        }
+        match msgs.split_first() {
+            Some((first, rest)) if self.produced_first == 0 => {
+                let c = self.produce(first);
+                self.queue.produce_n(rest);
+                c
+            }
+            _ => self.queue.produce_n(msgs),
+        }
+    }
+
+    /// Nonblocking [`Producer::produce_batch`], returning [`WriteError::Full`] if a lossless queue
+    /// doesn't have room for all msgs, or [`WriteError::TooLarge`] if it never will.
+    /// Nothing is produced in either case.
+    pub fn try_produce_batch(&mut self, msgs: &[T]) -> Result<usize, WriteError> {
+        if !self.queue.is_lossless() {
+            return Ok(self.produce_batch(msgs));
+        }
+        self.queue.try_produce_n(msgs, &mut self.read_count)
+    }
+
+    /// [`Producer::produce_batch`] for iterators. The messages are buffered up to a queue length
+    /// at a time, so only counts for messages the iterator actually yielded get claimed.
+    pub fn produce_iter<I: IntoIterator<Item = T>>(&mut self, msgs: I) -> usize {
+        let mut msgs = msgs.into_iter();
+        let mut buf = Vec::with_capacity(msgs.size_hint().0.min(self.queue.len()));
+        let mut first = None;
+        loop {
+            buf.clear();
+            buf.extend(msgs.by_ref().take(self.queue.len()));
+            if buf.is_empty() {
+                return first.unwrap_or_else(|| self.queue.count());
+            }
+            let c = self.produce_batch(&buf);
+            first.get_or_insert(c);
+        }
+    }
+
+    /// Publishes the current time and our pid in the queue header, for [`Consumer::producer_status`].
+    /// Producing does this every [`HEARTBEAT_EVERY`] messages, so this only has to be called
+    /// periodically while there is little or nothing to produce.
+    pub fn heartbeat(&self) {
+        self.queue.header.heartbeat.beat();
+    }
+
+    // Spins until f manages to claim room in a lossless queue
+    fn produce_lossless<F>(&mut self, mut f: F) -> usize
+        where F: FnMut(&Queue<T>, &mut usize) -> Result<usize, WriteError>
+    {
+        loop {
+            match f(self.queue, &mut self.read_count) {
+                Ok(c) => return c,
+                Err(WriteError::Full) => {
+                    pause();
+                }
+                Err(WriteError::TooLarge) => unreachable!("batches are split up to fit in the queue"),
+            }
+        }
+    }
+}
+
+impl<'a, T: Copy> From<&'a QueueHandle<T>> for Producer<'a, T> {
+    fn from(queue: &'a QueueHandle<T>) -> Self {
+        Self::from(&**queue)
+    }
 }
 
 impl<'a, T> AsMut<Producer<'a, T>> for Producer<'a, T> {
@@ -317,27 +810,116 @@ impl<'a, T> AsMut<Producer<'a, T>> for Producer<'a, T> {
     }
 }
 
+/// What a [`Consumer`] does after getting sped past. The jumps are computed from the producers'
+/// count, the producers may be overwriting the oldest message already so it can get sped past again.
+#[derive(Debug, Clone, Copy)]
+#[repr(u8)]
+pub enum RecoveryPolicy {
+    /// Continue at the next message the producers will write
+    Newest,
+    /// Continue at the oldest message still in the queue
+    Oldest,
+    /// [`Consumer::consume_or_err`] returns [`ReadError::SpedPast`], recovery is left to the caller.
+    /// The other blocking consumes continue at the newest message.
+    ReturnError,
+    /// Called with the count of the lost message and the producers' count, returns the count to
+    /// continue at. It gets clamped to the messages still in the queue.
+    Callback(fn(usize, usize) -> usize),
+}
+
+/// Where [`Consumer::starting_at`] starts reading. Positions of messages that were overwritten
+/// already are moved up to the oldest message still in the queue, which for lossless queues
+/// is the oldest unread one. Lossless queues also can't start past the producers' current count.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Start {
+    Oldest,
+    /// The next message the producers will write
+    Latest,
+    /// The message with this count, which may not have been produced yet on lossy queues
+    Sequence(usize),
+    /// This many messages before the next one the producers will write
+    Back(usize),
+}
+
 #[repr(C, align(64))]
 #[derive(Debug)]
-pub struct Consumer<'a, T> {
-    pub pos:              usize,        // 8
-    mask:                 usize,        // 16
-    pub expected_version: usize,        // 24
-    is_running:           u8,           // 25
-    _pad:                 [u8; 7],      // 32
-    pub queue:            &'a Queue<T>, // 48 fat ptr: (usize, pointer)
+pub struct Consumer<'a, T, W: WaitStrategy = BusySpin> {
+    pub pos:              usize,              // 8
+    mask:                 usize,              // 16
+    pub expected_version: usize,              // 24
+    is_running:           u8,                 // 25
+    lossless:             u8,                 // 26 publish read position to the queue header
+    _pad:                 [u8; 6],            // 32
+    pub queue:            &'a Queue<T>,       // 48 fat ptr: (usize, pointer)
+    cursor:               Option<&'a Cursor>, // 56 where named consumers persist their count
+    _pad2:                [u8; 8],            // 64
+    // Only touched after getting sped past, off the cacheline of the fields every consume uses
+    skipped:              usize,              // 72 messages lost to getting sped past
+    sped_past:            usize,              // 80
+    recovery:             RecoveryPolicy,     // 96
+    wait:                 W,                  // 96 for BusySpin
 }
 
-impl<'a, T: Copy> Consumer<'a, T> {
-    pub fn recover_after_error(&mut self) {
-        while self.queue.version_of(self.pos) > self.expected_version {
-            self.update_pos()
-        }
-        self.expected_version += 2;
+impl<'a, T: Copy, W: WaitStrategy> Consumer<'a, T, W> {
+    /// Switches to another [`WaitStrategy`] for [`Consumer::consume`]
+    pub fn with_wait<V: WaitStrategy>(self, wait: V) -> Consumer<'a, T, V> {
+        Consumer { pos: self.pos,
+                   mask: self.mask,
+                   expected_version: self.expected_version,
+                   is_running: self.is_running,
+                   lossless: self.lossless,
+                   _pad: self._pad,
+                   queue: self.queue,
+                   cursor: self.cursor,
+                   _pad2: self._pad2,
+                   skipped: self.skipped,
+                   sped_past: self.sped_past,
+                   recovery: self.recovery,
+                   wait }
+    }
+
+    /// Sets how to continue after getting sped past, see [`RecoveryPolicy`]
+    pub fn with_recovery(mut self, recovery: RecoveryPolicy) -> Self {
+        self.recovery = recovery;
+        self
+    }
+
+    /// Skips ahead after [`ReadError::SpedPast`] according to the [`RecoveryPolicy`],
+    /// returning how many messages were skipped. [`RecoveryPolicy::ReturnError`] jumps to the newest message.
+    pub fn recover_after_error(&mut self) -> usize {
+        let c = self.count();
+        let newest = self.queue.count();
+        let oldest = self.queue.oldest_at(newest);
+        let to = match self.recovery {
+            RecoveryPolicy::Newest | RecoveryPolicy::ReturnError => newest,
+            RecoveryPolicy::Oldest => oldest,
+            RecoveryPolicy::Callback(f) => f(c, newest).clamp(oldest, newest),
+        };
+        self.set_count(to);
+        self.account_skipped(c)
     }
 
-    pub fn recover_after_error_dumb(&mut self) {
-        self.expected_version += 2;
+    fn account_skipped(&mut self, from: usize) -> usize {
+        let skipped = self.count() - from;
+        self.skipped += skipped;
+        self.sped_past += 1;
+        skipped
+    }
+
+    /// Total number of messages skipped by [`Consumer::recover_after_error`]
+    pub fn skipped(&self) -> usize {
+        self.skipped
+    }
+
+    /// How many times this consumer recovered from getting sped past
+    pub fn sped_past(&self) -> usize {
+        self.sped_past
+    }
+
+    /// How many messages the consumer is behind the producers. Loads the producers' count,
+    /// so calling this on the hot path leads to false sharing.
+    pub fn lag(&self) -> usize {
+        self.queue.count().saturating_sub(self.count())
     }
 
     fn update_pos(&mut self) {
@@ -345,55 +927,269 @@ impl<'a, T: Copy> Consumer<'a, T> {
         self.expected_version += 2 * (self.pos == 0) as usize;
     }
 
-    /// Nonblocking consume returning either Ok(()) or a ReadError
-    pub fn try_consume(&mut self, el: &mut T) -> Result<(), ReadError> {
-        self.queue.consume(el, self.pos, self.expected_version)?;
+    /// The count of the next message this consumer will read
+    pub fn count(&self) -> usize {
+        ((self.expected_version - 2) >> 1) * (self.mask + 1) + self.pos
+    }
+
+    // Moves on to the next message after a successful read
+    fn advance(&mut self) {
         self.update_pos();
-        Ok(())
+        self.publish();
     }
 
-    /// Blocking consume
-    pub fn consume(&mut self, el: &mut T) {
-        loop {
-            match self.try_consume(el) {
-                Ok(_) => {
-                    return;
-                }
-                Err(ReadError::Empty) => {
-                    #[cfg(target_arch = "x86_64")]
-                    unsafe {
-                        std::arch::x86_64::_mm_pause()
-                    };
-                    continue;
-                }
-                Err(ReadError::SpedPast) => {
-                    self.recover_after_error();
+    // Jumps to the message with count c
+    pub(crate) fn set_count(&mut self, c: usize) {
+        self.pos = c & self.mask;
+        self.expected_version = self.queue.version_for(c);
+        if self.lossless == 1 {
+            // Jumps don't move the read count back behind what was already read
+            self.queue.advance_read_count(c);
+        }
+        self.store_cursor();
+    }
+
+    // Lets the producers of lossless queues know up to where we've read,
+    // and persists the count of named consumers
+    fn publish(&self) {
+        if self.lossless == 1 {
+            self.queue.store_read_count(self.count());
+        }
+        self.store_cursor();
+    }
+
+    fn store_cursor(&self) {
+        if let Some(cursor) = self.cursor {
+            cursor.store(self.count());
+        }
+    }
+
+    /// Nonblocking consume returning either the sequence number of the message, i.e. the
+    /// producers' count it was written at, or a ReadError
+    pub fn try_consume(&mut self, el: &mut T) -> Result<usize, ReadError> {
+        self.queue.consume(el, self.pos, self.expected_version)?;
+        let c = self.count();
+        self.advance();
+        Ok(c)
+    }
+
+    /// Nonblocking consume that runs f on the message in place instead of copying it out.
+    /// Its sequence number is `count() - 1` afterwards.
+    /// The result is discarded if the message got overwritten while f was running, so f may
+    /// see a torn message and should not have side effects.
+    pub fn try_consume_with<R, F: FnOnce(&T) -> R>(&mut self, f: F) -> Result<R, ReadError> {
+        let r = self.queue.consume_with(f, self.pos, self.expected_version)?;
+        self.advance();
+        Ok(r)
+    }
+
+    /// Blocking consume, waiting on empty queues according to the consumer's [`WaitStrategy`]
+    /// and recovering from getting sped past according to its [`RecoveryPolicy`].
+    /// Returns the sequence number of the message, see [`Consumer::try_consume`].
+    pub fn consume(&mut self, el: &mut T) -> usize {
+        self.blocking(|c| c.try_consume(el), |_| None::<Infallible>).unwrap_or_else(|e| match e {})
+    }
+
+    /// [`Consumer::consume`] that returns [`ReadError::SpedPast`] instead of recovering
+    /// when the consumer has [`RecoveryPolicy::ReturnError`].
+    pub fn consume_or_err(&mut self, el: &mut T) -> Result<usize, ReadError> {
+        let return_error = matches!(self.recovery, RecoveryPolicy::ReturnError);
+        self.blocking(|c| c.try_consume(el), |e| (return_error && e == ReadError::SpedPast).then_some(e))
+    }
+
+    /// [`Consumer::consume`] that gives up with [`Timeout`] once deadline has passed.
+    /// Depending on the [`WaitStrategy`] the deadline can be overshot by one of its sleeps.
+    pub fn consume_until(&mut self, el: &mut T, deadline: Instant) -> Result<usize, Timeout> {
+        self.blocking(|c| c.try_consume(el),
+                      |e| (e == ReadError::Empty && Instant::now() >= deadline).then_some(Timeout))
+    }
+
+    /// [`Consumer::try_consume`] that also returns the timestamp the producer stamped the message with,
+    /// see [`Queue::new_with_clock`]. The timestamp is 0 for queues without a [`Clock`].
+    pub fn try_consume_stamped(&mut self, el: &mut T) -> Result<(usize, u64), ReadError> {
+        let stamp = self.queue.consume_stamped(el, self.pos, self.expected_version)?;
+        let c = self.count();
+        self.advance();
+        Ok((c, stamp))
+    }
+
+    /// Blocking version of [`Consumer::try_consume_stamped`], see [`Consumer::consume`]
+    pub fn consume_stamped(&mut self, el: &mut T) -> (usize, u64) {
+        self.blocking(|c| c.try_consume_stamped(el), |_| None::<Infallible>).unwrap_or_else(|e| match e {})
+    }
+
+    // Waits on an empty queue and recovers from getting sped past until try_consume gets a message,
+    // or give_up returns the error to stop with. Only gives up on what give_up looks at, e.g. the clock.
+    fn blocking<R, E, F, G>(&mut self, mut try_consume: F, mut give_up: G) -> Result<R, E>
+        where F: FnMut(&mut Self) -> Result<R, ReadError>,
+              G: FnMut(ReadError) -> Option<E>
+    {
+        let r = loop {
+            match try_consume(self) {
+                Ok(r) => break Ok(r),
+                Err(e) => {
+                    if let Some(e) = give_up(e) {
+                        break Err(e);
+                    }
+                    match e {
+                        ReadError::Empty => self.wait.wait(&self.queue.header.notifier),
+                        ReadError::SpedPast => {
+                            self.recover_after_error();
+                        }
+                    }
                 }
             }
+        };
+        self.wait.reset(&self.queue.header.notifier);
+        r
+    }
+
+    pub fn consume_timeout(&mut self, el: &mut T, timeout: Duration) -> Result<usize, Timeout> {
+        self.consume_until(el, Instant::now() + timeout)
+    }
+
+    /// Nonblocking consume of all available messages that fit in els.
+    /// Returns how many were read, or the error if not a single one could be.
+    ///
+    /// When the consumer gets sped past mid-batch, the messages read up to that point are returned
+    /// and the following call will return [`ReadError::SpedPast`].
+    pub fn consume_available(&mut self, els: &mut [T]) -> Result<usize, ReadError> {
+        for (i, el) in els.iter_mut().enumerate() {
+            if let Err(e) = self.try_consume(el) {
+                return if i == 0 { Err(e) } else { Ok(i) };
+            }
+        }
+        Ok(els.len())
+    }
+
+    /// Whether the producer is alive, based on its heartbeats, see [`Producer::heartbeat`].
+    /// It is considered stale if the last one is older than stale_after.
+    pub fn producer_status(&self, stale_after: Duration) -> ProducerStatus {
+        self.queue.header.heartbeat.status(stale_after)
+    }
+
+    /// Iterator over the messages available up to the producer's current count.
+    /// After yielding [`ReadError::SpedPast`] it stops, leaving the recovery to the caller.
+    pub fn drain(&mut self) -> Drain<'_, 'a, T, W> {
+        // This is the only read of the producer's count, so the false sharing is limited to one load
+        let end = self.queue.count();
+        Drain { consumer: self, end, done: false }
+    }
+
+}
+
+impl<'a, T: Copy> Consumer<'a, T> {
+    /// Consumer starting at a position other than the producers' current one, see [`Start`].
+    pub fn starting_at(queue: &'a Queue<T>, start: Start) -> Self {
+        let mut consumer = Self::from(queue);
+        let newest = queue.count();
+        let oldest = queue.oldest_at(newest);
+        let c = match start {
+            Start::Oldest => oldest,
+            Start::Latest => newest,
+            Start::Sequence(n) => n.max(oldest),
+            Start::Back(k) => newest.saturating_sub(k).max(oldest),
+        };
+        // A read count ahead of the producers would leave them waiting on messages that were never written
+        let c = if queue.is_lossless() { c.min(newest) } else { c };
+        consumer.set_count(c);
+        consumer
+    }
+
+    /// Consumer that persists its count in the queue header under name, so that a consumer
+    /// opened later with the same name, e.g. after a restart, resumes where this one left off.
+    /// A new name starts like [`Consumer::from`]. Names are shared with [`crate::GroupConsumer`].
+    ///
+    /// Also returns how many messages were lost because the producers overwrote them in the
+    /// meantime, or for lossless queues another consumer read them, in which case it resumes at
+    /// the oldest message still in the queue.
+    pub fn named(queue: &'a Queue<T>, name: &str) -> Result<(Self, usize), QueueError> {
+        let mut consumer = Self::from(queue);
+        let cursor = cursor::find_or_claim(queue.header.cursors(), name, || consumer.count())?;
+        let mut c = cursor.count();
+        let oldest = queue.oldest();
+        let lost = oldest.saturating_sub(c);
+        if lost != 0 {
+            cursor.mark_sped_past();
+            c = oldest;
         }
+        consumer.cursor = Some(cursor);
+        consumer.set_count(c);
+        Ok((consumer, lost))
     }
 
     pub fn init_header(consumer_ptr: *mut Consumer<T>, queue: &'static Queue<T>) {
-        unsafe {
-            (*consumer_ptr).pos = queue.cur_pos();
-            (*consumer_ptr).expected_version = queue.version();
-            (*consumer_ptr).mask = queue.header.mask;
-            (*consumer_ptr).queue = queue
+        unsafe { consumer_ptr.write(Consumer::from(queue)) }
+    }
+}
+
+pub struct Drain<'b, 'a, T, W: WaitStrategy = BusySpin> {
+    consumer: &'b mut Consumer<'a, T, W>,
+    end:      usize,
+    done:     bool,
+}
+
+impl<'b, 'a, T: Copy, W: WaitStrategy> Iterator for Drain<'b, 'a, T, W> {
+    type Item = Result<T, ReadError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done || self.consumer.count() == self.end {
+            return None;
+        }
+        match self.consumer.try_consume_with(|m| *m) {
+            Ok(m) => Some(Ok(m)),
+            // MPMC producers may still be writing a claimed slot
+            Err(ReadError::Empty) => None,
+            Err(e) => {
+                self.done = true;
+                Some(Err(e))
+            }
         }
     }
 }
 
-impl<'a, T> AsMut<Consumer<'a, T>> for Consumer<'a, T> {
-    fn as_mut(&mut self) -> &mut Consumer<'a, T> {
+impl<'a, T, W: WaitStrategy> AsMut<Consumer<'a, T, W>> for Consumer<'a, T, W> {
+    fn as_mut(&mut self) -> &mut Consumer<'a, T, W> {
         self
     }
 }
 
+// Unregisters consumers that are dropped while parked, e.g. when unwinding out of a consume
+impl<'a, T, W: WaitStrategy> Drop for Consumer<'a, T, W> {
+    fn drop(&mut self) {
+        self.wait.reset(&self.queue.header.notifier);
+    }
+}
+
 impl<'a, T: Copy> From<&'a Queue<T>> for Consumer<'a, T> {
     fn from(queue: &'a Queue<T>) -> Self {
-        let pos = queue.cur_pos();
-        let expected_version = queue.version();
-        Self { pos, mask: queue.header.mask, _pad: [0; 7], expected_version, is_running: 1, queue }
+        let lossless = queue.is_lossless();
+        // Lossless queues have to continue where the last consumer left off,
+        // otherwise the producer would wait forever on the skipped messages
+        let c = if lossless { queue.header.read_count.load(Ordering::Acquire) } else { queue.count() };
+        let pos = c & queue.header.mask;
+        let expected_version = queue.version_for(c);
+        Self {
+            pos,
+            mask: queue.header.mask,
+            _pad: [0; 6],
+            expected_version,
+            is_running: 1,
+            lossless: lossless as u8,
+            queue,
+            cursor: None,
+            _pad2: [0; 8],
+            skipped: 0,
+            sped_past: 0,
+            recovery: RecoveryPolicy::Newest,
+            wait: BusySpin,
+        }
+    }
+}
+
+impl<'a, T: Copy> From<&'a QueueHandle<T>> for Consumer<'a, T> {
+    fn from(queue: &'a QueueHandle<T>) -> Self {
+        Self::from(&**queue)
     }
 }
 
@@ -408,20 +1204,27 @@ mod test {
     }
     #[test]
     fn headersize() {
-        assert_eq!(64, std::mem::size_of::<QueueHeader>());
-        assert_eq!(64, std::mem::size_of::<Consumer<'_, [u8; 60]>>())
+        assert_eq!(640, std::mem::size_of::<QueueHeader>());
+        type C = Consumer<'static, [u8; 60]>;
+        assert_eq!(128, std::mem::size_of::<C>());
+        // Everything a consume touches shares the first cacheline
+        assert_eq!(0, std::mem::offset_of!(C, pos));
+        assert_eq!(16, std::mem::offset_of!(C, expected_version));
+        assert_eq!(32, std::mem::offset_of!(C, queue));
+        assert_eq!(48, std::mem::offset_of!(C, cursor));
+        assert_eq!(64, std::mem::offset_of!(C, skipped));
     }
 
     #[test]
     fn basic() {
         for typ in [QueueType::SPMC, QueueType::MPMC] {
             let q = Queue::new(16, typ).unwrap();
-            let mut p = Producer::from(q);
-            let mut c = Consumer::from(q);
+            let mut p = Producer::from(&q);
+            let mut c = Consumer::from(&q);
             p.produce(&1);
             let mut m = 0;
 
-            assert_eq!(c.try_consume(&mut m), Ok(()));
+            assert_eq!(c.try_consume(&mut m), Ok(0));
             assert_eq!(m, 1);
             assert!(matches!(c.try_consume(&mut m), Err(ReadError::Empty)));
             for i in 0..16 {
@@ -442,45 +1245,315 @@ mod test {
         }
     }
 
-    fn multithread(n_writers: usize, n_readers: usize, tot_messages: usize) {
-        let q = Queue::new(16, QueueType::MPMC).unwrap();
+    fn spsc_full_and_resume(q: &Queue<usize>) {
+        let mut p = Producer::from(q);
+        let mut c = Consumer::from(q);
+        for i in 0..16 {
+            assert_eq!(p.try_produce(&i), Ok(i));
+        }
+        assert_eq!(p.try_produce(&16), Err(WriteError::Full));
+
+        let mut m = 0;
+        c.try_consume(&mut m).unwrap();
+        assert_eq!(m, 0);
+        assert_eq!(p.try_produce(&16), Ok(16));
+        assert_eq!(p.try_produce(&17), Err(WriteError::Full));
 
-        let mut readhandles = Vec::new();
-        for n in 0..n_readers {
-            let mut c1 = Consumer::from(&*q);
-            let cons = std::thread::spawn(move || {
-                let mut c = 0;
+        // A new consumer picks up where the previous one left off
+        let mut c = Consumer::from(q);
+        for i in 1..17 {
+            c.try_consume(&mut m).unwrap();
+            assert_eq!(m, i);
+        }
+        assert!(matches!(c.try_consume(&mut m), Err(ReadError::Empty)));
+    }
+
+    #[test]
+    fn spsc() {
+        let q = Queue::new(16, QueueType::SPSC).unwrap();
+        spsc_full_and_resume(&q);
+    }
+
+    #[test]
+    fn spsc_multithread() {
+        let q = Queue::new(16, QueueType::SPSC).unwrap();
+        std::thread::scope(|s| {
+            let tot_messages = 100000;
+            let mut c1 = Consumer::from(&q);
+            let cons = s.spawn(move || {
                 let mut m = 0;
-                while c < tot_messages {
+                for i in 0..tot_messages {
                     c1.consume(&mut m);
-                    c += m;
+                    assert_eq!(m, i);
                 }
-                assert_eq!(c, (0..tot_messages).sum::<usize>());
             });
-            readhandles.push(cons)
-        }
-        let mut writehandles = Vec::new();
-        for n in 0..n_writers {
-            let mut p1 = Producer::from(&*q);
-            let prod1 = std::thread::spawn(move || {
-                std::thread::sleep(std::time::Duration::from_millis(20));
-                let mut c = n;
-                while c < tot_messages {
-                    p1.produce(&c);
-                    c += n_writers;
-                    std::thread::yield_now();
+            let mut p1 = Producer::from(&q);
+            for i in 0..tot_messages {
+                p1.produce(&i);
+            }
+            cons.join().unwrap();
+        });
+    }
+
+    #[test]
+    fn mpsc() {
+        let q = Queue::new(16, QueueType::MPSC).unwrap();
+        spsc_full_and_resume(&q);
+    }
+
+    fn mpsc_multithread(n_writers: usize, msgs_per_writer: usize) {
+        let q = Queue::new(16, QueueType::MPSC).unwrap();
+        std::thread::scope(|s| {
+            let mut c1 = Consumer::from(&q);
+            let cons = s.spawn(move || {
+                let mut last = vec![None; n_writers];
+                let mut m = (0, 0);
+                for _ in 0..n_writers * msgs_per_writer {
+                    c1.consume(&mut m);
+                    let (writer, i) = m;
+                    // Messages of each writer arrive in order without gaps
+                    assert_eq!(last[writer].map_or(0, |l| l + 1), i);
+                    last[writer] = Some(i);
                 }
+                assert!(matches!(c1.try_consume(&mut m), Err(ReadError::Empty)));
             });
-            writehandles.push(prod1);
+            let mut writehandles = Vec::new();
+            for n in 0..n_writers {
+                let mut p1 = Producer::from(&q);
+                writehandles.push(s.spawn(move || {
+                    for i in 0..msgs_per_writer {
+                        p1.produce(&(n, i));
+                        std::thread::yield_now();
+                    }
+                }));
+            }
+            for h in writehandles {
+                h.join().unwrap();
+            }
+            cons.join().unwrap();
+        });
+    }
+    #[test]
+    fn mpsc_multithread_2() {
+        mpsc_multithread(2, 5000);
+    }
+    #[test]
+    fn mpsc_multithread_8() {
+        mpsc_multithread(8, 1000);
+    }
+
+    #[test]
+    fn batch() {
+        for typ in [QueueType::SPMC, QueueType::MPMC, QueueType::SPSC, QueueType::MPSC] {
+            let q = Queue::new(16, typ).unwrap();
+            let mut p = Producer::from(&q);
+            let mut c = Consumer::from(&q);
+            let mut m = 0;
+
+            assert_eq!(p.produce_batch(&[0, 1, 2, 3]), 0);
+            assert_eq!(p.produce_iter(4..10), 4);
+            assert_eq!(p.produce_batch(&[]), 10);
+            for i in 0..10 {
+                c.try_consume(&mut m).unwrap();
+                assert_eq!(m, i);
+            }
+            assert!(matches!(c.try_consume(&mut m), Err(ReadError::Empty)));
+
+            if q.is_lossless() {
+                assert_eq!(p.try_produce_batch(&[0; 17]), Err(WriteError::TooLarge));
+                assert_eq!(p.try_produce_batch(&[10; 16]), Ok(10));
+                assert_eq!(p.try_produce_batch(&[0]), Err(WriteError::Full));
+            } else {
+                p.produce_batch(&[1; 20]);
+                assert!(matches!(c.try_consume(&mut m), Err(ReadError::SpedPast)));
+            }
+        }
+    }
+
+    // Iterators whose len is off only get the counts of what they yield
+    #[test]
+    fn produce_iter_unknown_len() {
+        struct Lying(std::ops::Range<usize>);
+        impl Iterator for Lying {
+            type Item = usize;
+            fn next(&mut self) -> Option<usize> {
+                self.0.next()
+            }
+            fn size_hint(&self) -> (usize, Option<usize>) {
+                (100, Some(100))
+            }
+        }
+        for typ in [QueueType::SPMC, QueueType::SPSC] {
+            let q = Queue::new(16, typ).unwrap();
+            let mut p = Producer::from(&q);
+            let mut c = Consumer::from(&q);
+            assert_eq!(p.produce_iter(Lying(0..5)), 0);
+            assert_eq!(p.produce_iter((5..40).filter(|i| i % 5 == 0)), 5);
+            assert_eq!(q.count(), 12);
+            let mut m = 0;
+            for i in (0..5).chain((5..40).step_by(5)) {
+                c.try_consume(&mut m).unwrap();
+                assert_eq!(m, i);
+            }
+            assert_eq!(c.try_consume(&mut m), Err(ReadError::Empty));
+        }
+    }
+
+    #[test]
+    fn produce_with() {
+        for typ in [QueueType::SPMC, QueueType::MPMC, QueueType::SPSC, QueueType::MPSC] {
+            let q = Queue::new(16, typ).unwrap();
+            let mut p = Producer::from(&q);
+            let mut c = Consumer::from(&q);
+            let mut m = [0usize; 4];
+            for i in 0..10 {
+                assert_eq!(p.produce_with(|slot| {
+                               slot.write([i; 4]);
+                           }),
+                           i);
+            }
+            for i in 0..10 {
+                c.try_consume(&mut m).unwrap();
+                assert_eq!(m, [i; 4]);
+            }
+            if q.is_lossless() {
+                for i in 0..16 {
+                    p.produce_with(|slot| {
+                         slot.write([i; 4]);
+                     });
+                }
+                assert_eq!(p.try_produce_with(|_| panic!("queue is full")), Err(WriteError::Full));
+            }
+        }
+    }
+
+    #[test]
+    fn try_consume_with() {
+        for typ in [QueueType::SPMC, QueueType::MPMC, QueueType::SPSC, QueueType::MPSC] {
+            let q = Queue::new(16, typ).unwrap();
+            let mut p = Producer::from(&q);
+            let mut c = Consumer::from(&q);
+            assert_eq!(c.try_consume_with(|m: &[usize; 4]| m[1]), Err(ReadError::Empty));
+            for i in 0..10 {
+                p.produce(&[i; 4]);
+            }
+            for i in 0..10 {
+                assert_eq!(c.try_consume_with(|m| m[1]), Ok(i));
+            }
+            assert_eq!(c.try_consume_with(|m| m[1]), Err(ReadError::Empty));
+            if !q.is_lossless() {
+                p.produce_iter((0..20).map(|i| [i; 4]));
+                assert_eq!(c.try_consume_with(|m| m[1]), Err(ReadError::SpedPast));
+            }
         }
+    }
+
+    #[test]
+    fn consume_available() {
+        for typ in [QueueType::SPMC, QueueType::MPMC, QueueType::SPSC, QueueType::MPSC] {
+            let q = Queue::new(16, typ).unwrap();
+            let mut p = Producer::from(&q);
+            let mut c = Consumer::from(&q);
+            let mut buf = [0; 16];
+            assert_eq!(c.consume_available(&mut buf), Err(ReadError::Empty));
 
-        for h in readhandles {
-            h.join();
+            p.produce_iter(0..10);
+            assert_eq!(c.consume_available(&mut buf[..4]), Ok(4));
+            assert_eq!(buf[..4], [0, 1, 2, 3]);
+            assert_eq!(c.consume_available(&mut buf), Ok(6));
+            assert_eq!(buf[..6], [4, 5, 6, 7, 8, 9]);
+            assert_eq!(c.consume_available(&mut buf), Err(ReadError::Empty));
+
+            if !q.is_lossless() {
+                p.produce_iter(0..20);
+                assert_eq!(c.consume_available(&mut buf), Err(ReadError::SpedPast));
+                assert_eq!(c.count(), 10);
+            }
         }
-        for h in writehandles {
-            h.join();
+    }
+
+    #[test]
+    fn drain() {
+        for typ in [QueueType::SPMC, QueueType::MPMC, QueueType::SPSC, QueueType::MPSC] {
+            let q = Queue::new(16, typ).unwrap();
+            let mut p = Producer::from(&q);
+            let mut c = Consumer::from(&q);
+            assert_eq!(c.drain().count(), 0);
+
+            p.produce_iter(0..10);
+            assert_eq!(c.drain().collect::<Result<Vec<_>, _>>(), Ok((0..10).collect()));
+            assert_eq!(c.drain().count(), 0);
+
+            if !q.is_lossless() {
+                p.produce_iter(0..20);
+                let mut d = c.drain();
+                assert_eq!(d.next(), Some(Err(ReadError::SpedPast)));
+                assert_eq!(d.next(), None);
+            }
         }
     }
+
+    #[test]
+    fn batch_lossless_larger_than_queue() {
+        let q = Queue::new(16, QueueType::SPSC).unwrap();
+        std::thread::scope(|s| {
+            let tot_messages = 1000;
+            let mut c1 = Consumer::from(&q);
+            let cons = s.spawn(move || {
+                let mut m = 0;
+                for i in 0..tot_messages {
+                    c1.consume(&mut m);
+                    assert_eq!(m, i);
+                }
+            });
+            let mut p1 = Producer::from(&q);
+            let msgs = (0..tot_messages / 2).collect::<Vec<_>>();
+            assert_eq!(p1.produce_batch(&msgs), 0);
+            assert_eq!(p1.produce_iter(tot_messages / 2..tot_messages), tot_messages / 2);
+            cons.join().unwrap();
+        });
+    }
+
+    fn multithread(n_writers: usize, n_readers: usize, tot_messages: usize) {
+        let q = Queue::new(16, QueueType::MPMC).unwrap();
+        std::thread::scope(|s| {
+            let mut readhandles = Vec::new();
+            for n in 0..n_readers {
+                let mut c1 = Consumer::from(&q);
+                let cons = s.spawn(move || {
+                    let mut c = 0;
+                    let mut m = 0;
+                    while c < tot_messages {
+                        c1.consume(&mut m);
+                        c += m;
+                    }
+                    assert_eq!(c, (0..tot_messages).sum::<usize>());
+                });
+                readhandles.push(cons)
+            }
+            let mut writehandles = Vec::new();
+            for n in 0..n_writers {
+                let mut p1 = Producer::from(&q);
+                let prod1 = s.spawn(move || {
+                    std::thread::sleep(std::time::Duration::from_millis(20));
+                    let mut c = n;
+                    while c < tot_messages {
+                        p1.produce(&c);
+                        c += n_writers;
+                        std::thread::yield_now();
+                    }
+                });
+                writehandles.push(prod1);
+            }
+
+            for h in readhandles {
+                h.join();
+            }
+            for h in writehandles {
+                h.join();
+            }
+        });
+    }
     #[test]
     fn multithread_1_2() {
         multithread(1, 2, 100000);
@@ -507,14 +1580,15 @@ mod test {
         for typ in [QueueType::SPMC, QueueType::MPMC] {
             let path = std::path::Path::new("/dev/shm/blabla_test");
             std::fs::remove_file(path);
-            let q = Queue::shared(path, 16, typ).unwrap();
-            let mut p = Producer::from(q);
-            let mut c = Consumer::from(q);
+            let mut q = Queue::shared(path, 16, typ).unwrap();
+            q.set_owner(true);
+            let mut p = Producer::from(&q);
+            let mut c = Consumer::from(&q);
 
             p.produce(&1);
             let mut m = 0;
 
-            assert_eq!(c.try_consume(&mut m), Ok(()));
+            assert_eq!(c.try_consume(&mut m), Ok(0));
             assert_eq!(m, 1);
             assert!(matches!(c.try_consume(&mut m), Err(ReadError::Empty)));
             for i in 0..16 {
@@ -532,7 +1606,424 @@ mod test {
             }
 
             assert!(matches!(c.try_consume(&mut m), Err(ReadError::SpedPast)));
-            std::fs::remove_file(&path);
         }
     }
+
+    #[test]
+    #[cfg(feature = "shmem")]
+    fn spsc_shared() {
+        let path = std::path::Path::new("/dev/shm/spsc_test");
+        std::fs::remove_file(path);
+        let q = Queue::<usize>::shared(path, 16, QueueType::SPSC).unwrap();
+        spsc_full_and_resume(&Queue::open_shared(path).unwrap());
+        assert_eq!(q.header.read_count.load(Ordering::Relaxed), 17);
+        Queue::<usize>::unlink(path).unwrap();
+    }
+
+    #[test]
+    fn named() {
+        let q = Queue::new(16, QueueType::SPMC).unwrap();
+        let mut p = Producer::from(&q);
+        let (mut c, lost) = Consumer::named(&q, "risk").unwrap();
+        assert_eq!(lost, 0);
+        let mut m = 0;
+        for i in 0..5 {
+            p.produce(&i);
+        }
+        for i in 0..3 {
+            c.try_consume(&mut m).unwrap();
+            assert_eq!(m, i);
+        }
+        drop(c);
+        p.produce(&5);
+
+        let (mut c, lost) = Consumer::named(&q, "risk").unwrap();
+        assert_eq!(lost, 0);
+        c.try_consume(&mut m).unwrap();
+        assert_eq!(m, 3);
+        drop(c);
+
+        for i in 6..30 {
+            p.produce(&i);
+        }
+        let (mut c, lost) = Consumer::named(&q, "risk").unwrap();
+        assert_eq!(lost, 10);
+        assert_eq!(q.header.cursors()[0].sped_past(), 1);
+        c.try_consume(&mut m).unwrap();
+        assert_eq!(m, 14);
+    }
+
+    #[test]
+    fn named_lossless_behind() {
+        let q = Queue::new(16, QueueType::SPSC).unwrap();
+        let mut p = Producer::from(&q);
+        let (mut c, _) = Consumer::named(&q, "risk").unwrap();
+        let mut m = 0;
+        for i in 0..8 {
+            p.produce(&i);
+        }
+        for _ in 0..2 {
+            c.try_consume(&mut m).unwrap();
+        }
+        drop(c);
+        let mut other = Consumer::from(&q);
+        for _ in 0..3 {
+            other.try_consume(&mut m).unwrap();
+        }
+        assert_eq!(q.read_count(), 5);
+
+        let (mut c, lost) = Consumer::named(&q, "risk").unwrap();
+        assert_eq!(lost, 3);
+        assert_eq!(q.read_count(), 5);
+        c.try_consume(&mut m).unwrap();
+        assert_eq!(m, 5);
+
+        // A read count ahead of the producers errors instead of spinning
+        for i in 8..16 {
+            p.produce(&i);
+        }
+        q.header.read_count.store(100, Ordering::Relaxed);
+        assert_eq!(Producer::from(&q).try_produce(&0), Err(WriteError::Full));
+    }
+
+    #[test]
+    #[cfg(feature = "shmem")]
+    fn named_shared() {
+        let path = std::path::Path::new("/dev/shm/named_shared_test");
+        let _ = std::fs::remove_file(path);
+        let q = Queue::<usize>::shared(path, 16, QueueType::SPSC).unwrap();
+        let mut p = Producer::from(&q);
+        {
+            let q2 = Queue::<usize>::open_shared(path).unwrap();
+            let (mut c, _) = Consumer::named(&q2, "risk").unwrap();
+            for i in 0..10 {
+                p.produce(&i);
+            }
+            let mut m = 0;
+            for _ in 0..4 {
+                c.try_consume(&mut m).unwrap();
+            }
+        }
+        let q2 = Queue::<usize>::open_shared(path).unwrap();
+        let (mut c, lost) = Consumer::named(&q2, "risk").unwrap();
+        assert_eq!(lost, 0);
+        let mut m = 0;
+        c.try_consume(&mut m).unwrap();
+        assert_eq!(m, 4);
+        Queue::<usize>::unlink(path).unwrap();
+    }
+
+    #[test]
+    fn fingerprint_mismatch() {
+        let q = Queue::<[u8; 56]>::new(16, QueueType::SPMC).unwrap();
+        let ptr = &q.header as *const QueueHeader as *mut QueueHeader;
+        unsafe {
+            assert!(Queue::<[u8; 56]>::from_initialized_ptr(ptr).is_ok());
+            assert!(matches!(Queue::<[u8; 48]>::from_initialized_ptr(ptr), Err(QueueError::FingerprintMismatch { .. })));
+            // Same size and alignment, different type
+            assert!(matches!(Queue::<[i8; 56]>::from_initialized_ptr(ptr), Err(QueueError::FingerprintMismatch { .. })));
+            assert!(matches!(Queue::<[u64; 7]>::from_initialized_ptr(ptr), Err(QueueError::FingerprintMismatch { .. })));
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "shmem")]
+    fn fingerprint_mismatch_shared() {
+        let path = std::path::Path::new("/dev/shm/fingerprint_test");
+        let _ = std::fs::remove_file(path);
+        let _q = Queue::<usize>::shared(path, 16, QueueType::SPMC).unwrap();
+        assert!(Queue::<usize>::open_shared(path).is_ok());
+        assert!(matches!(Queue::<u64>::open_shared(path), Err(QueueError::FingerprintMismatch { .. })));
+        assert!(matches!(Queue::<(u32, u32)>::open_shared(path), Err(QueueError::FingerprintMismatch { .. })));
+        Queue::<usize>::unlink(path).unwrap();
+    }
+
+    #[test]
+    #[cfg(feature = "shmem")]
+    fn unlink() {
+        let path = std::path::Path::new("/dev/shm/unlink_test");
+        let _ = std::fs::remove_file(path);
+        let q = Queue::<usize>::shared(path, 16, QueueType::SPMC).unwrap();
+        let c = Consumer::from(&q);
+        assert!(!c.queue.is_removed());
+        Queue::<usize>::unlink(path).unwrap();
+        assert!(c.queue.is_removed());
+        assert!(!path.exists());
+        assert!(Queue::<usize>::open_shared(path).is_err());
+        assert!(Queue::<usize>::unlink(path).is_err());
+
+        let mut q = Queue::<usize>::shared(path, 16, QueueType::SPMC).unwrap();
+        q.set_owner(true);
+        let q2 = Queue::<usize>::open_shared(path).unwrap();
+        assert!(!q2.is_removed());
+        drop(q);
+        assert!(q2.is_removed());
+        assert!(!path.exists());
+    }
+
+    #[test]
+    #[cfg(feature = "shmem")]
+    fn unlink_other_type_or_layout() {
+        let path = std::path::Path::new("/dev/shm/unlink_other_test");
+        let _ = std::fs::remove_file(path);
+        let q = Queue::<[u8; 56]>::shared(path, 16, QueueType::SPMC).unwrap();
+        Queue::<usize>::unlink(path).unwrap();
+        assert!(q.is_removed());
+        assert!(!path.exists());
+
+        let q = Queue::<usize>::shared(path, 16, QueueType::SPMC).unwrap();
+        let header = QueueHeader::from_ptr(&q.header as *const QueueHeader as *mut u8);
+        header.fingerprint.layout_version -= 1;
+        Queue::<usize>::unlink(path).unwrap();
+        assert!(!q.is_removed());
+        assert!(!path.exists());
+        assert!(Queue::<usize>::unlink(path).is_err());
+    }
+
+    #[test]
+    fn producer_status() {
+        let q = Queue::<usize>::new(16, QueueType::SPMC).unwrap();
+        let p = Producer::from(&q);
+        let c = Consumer::from(&q);
+        assert_eq!(c.producer_status(std::time::Duration::from_secs(1)), ProducerStatus::Unknown);
+        p.heartbeat();
+        assert_eq!(c.producer_status(std::time::Duration::from_secs(10)), ProducerStatus::Alive);
+
+        // Producing stamps it every HEARTBEAT_EVERY messages
+        for typ in [QueueType::SPMC, QueueType::MPMC, QueueType::SPSC, QueueType::MPSC] {
+            let q = Queue::<usize>::new(16, typ).unwrap();
+            q.header.count.store(1, Ordering::Relaxed);
+            q.header.read_count.store(1, Ordering::Relaxed);
+            let mut p = Producer::from(&q);
+            let c = Consumer::from(&q);
+            p.produce(&1);
+            assert_eq!(c.producer_status(std::time::Duration::from_secs(10)), ProducerStatus::Unknown);
+            q.header.count.store(HEARTBEAT_EVERY - 1, Ordering::Relaxed);
+            q.header.read_count.store(HEARTBEAT_EVERY - 1, Ordering::Relaxed);
+            p.produce_batch(&[0; 4]);
+            assert_eq!(c.producer_status(std::time::Duration::from_secs(10)), ProducerStatus::Alive);
+        }
+    }
+
+    fn wait_strategy<W: WaitStrategy + Send>(typ: QueueType, wait: W) {
+        let q = Queue::<usize>::new(16, typ).unwrap();
+        let mut c = Consumer::from(&q).with_wait(wait);
+        std::thread::scope(|s| {
+            s.spawn(|| {
+                 let mut p = Producer::from(&q);
+                 for i in 0..100 {
+                     if i % 10 == 0 {
+                         std::thread::sleep(std::time::Duration::from_millis(1));
+                     }
+                     p.produce(&i);
+                 }
+             });
+            let mut m = 0;
+            for i in 0..100 {
+                c.consume(&mut m);
+                assert_eq!(m, i);
+            }
+        });
+        assert_eq!(q.header.notifier().waiters(), 0);
+    }
+
+    #[test]
+    fn wait_strategies() {
+        use crate::wait::{Park, SpinSleep, SpinYield};
+        wait_strategy(QueueType::SPSC, SpinYield::new(10));
+        wait_strategy(QueueType::SPSC, SpinSleep::default());
+        wait_strategy(QueueType::SPSC, Park::new(10, std::time::Duration::from_secs(10)));
+        wait_strategy(QueueType::MPSC, Park::new(0, std::time::Duration::from_secs(10)));
+    }
+
+    #[test]
+    fn drop_parked() {
+        let q = Queue::<usize>::new(16, QueueType::SPMC).unwrap();
+        let mut c = Consumer::from(&q).with_wait(crate::wait::Park::new(0, Duration::from_millis(1)));
+        c.wait.wait(q.header.notifier());
+        assert_eq!(q.header.notifier().waiters(), 1);
+        drop(c);
+        assert_eq!(q.header.notifier().waiters(), 0);
+    }
+
+    #[test]
+    fn consume_timeout() {
+        let q = Queue::<usize>::new(16, QueueType::SPMC).unwrap();
+        let mut p = Producer::from(&q);
+        let mut c = Consumer::from(&q).with_wait(crate::wait::SpinSleep::default());
+        let mut m = 0;
+        assert_eq!(c.consume_timeout(&mut m, Duration::from_millis(5)), Err(Timeout));
+        p.produce(&1);
+        c.consume_timeout(&mut m, Duration::from_millis(5)).unwrap();
+        assert_eq!(m, 1);
+        assert_eq!(c.consume_until(&mut m, Instant::now()), Err(Timeout));
+    }
+
+    #[test]
+    fn loss_accounting() {
+        let q = Queue::<usize>::new(16, QueueType::SPMC).unwrap();
+        let mut p = Producer::from(&q);
+        let mut c = Consumer::from(&q);
+        for i in 0..40 {
+            p.produce(&i);
+        }
+        assert_eq!(c.lag(), 40);
+        let mut m = 0;
+        assert_eq!(c.try_consume(&mut m), Err(ReadError::SpedPast));
+        // Recovery continues at the producer's position
+        assert_eq!(c.recover_after_error(), 40);
+        assert_eq!((c.skipped(), c.sped_past(), c.lag()), (40, 1, 0));
+        p.produce(&40);
+        assert_eq!(c.lag(), 1);
+        c.try_consume(&mut m).unwrap();
+        assert_eq!(m, 40);
+    }
+
+    #[test]
+    fn recovery_policies() {
+        let q = Queue::<usize>::new(16, QueueType::SPMC).unwrap();
+        let mut p = Producer::from(&q);
+        let mut newest = Consumer::from(&q);
+        let mut oldest = Consumer::from(&q).with_recovery(RecoveryPolicy::Oldest);
+        let mut error = Consumer::from(&q).with_recovery(RecoveryPolicy::ReturnError);
+        let mut halfway = Consumer::from(&q).with_recovery(RecoveryPolicy::Callback(|lost, newest| (lost + newest) / 2));
+        for i in 0..40 {
+            p.produce(&i);
+        }
+        let mut m = 0;
+        assert_eq!(newest.consume_timeout(&mut m, Duration::ZERO), Err(Timeout));
+        assert_eq!(newest.skipped(), 40);
+        oldest.consume(&mut m);
+        assert_eq!((m, oldest.skipped()), (24, 24));
+        assert_eq!(error.consume_or_err(&mut m), Err(ReadError::SpedPast));
+        assert_eq!(error.count(), 0);
+        // Clamped to the oldest message
+        halfway.consume(&mut m);
+        assert_eq!(m, 24);
+    }
+
+    #[test]
+    fn recovery_while_lapped() {
+        let q = Queue::<usize>::new(16, QueueType::SPMC).unwrap();
+        let done = AtomicBool::new(false);
+        std::thread::scope(|s| {
+            s.spawn(|| {
+                let mut p = Producer::from(&q);
+                let mut i = 0;
+                while !done.load(Ordering::Relaxed) {
+                    p.produce(&i);
+                    i += 1;
+                }
+            });
+            let mut c = Consumer::from(&q).with_recovery(RecoveryPolicy::Callback(|lost, _| lost));
+            let mut m = 0;
+            for _ in 0..100_000 {
+                c.consume(&mut m);
+            }
+            done.store(true, Ordering::Relaxed);
+        });
+    }
+
+    #[test]
+    fn starting_at() {
+        let q = Queue::<usize>::new(16, QueueType::SPMC).unwrap();
+        let mut p = Producer::from(&q);
+        for i in 0..5 {
+            p.produce(&i);
+        }
+        assert_eq!(Consumer::starting_at(&q, Start::Oldest).count(), 0);
+        for i in 5..40 {
+            p.produce(&i);
+        }
+        let mut m = 0;
+        let mut check = |start, expected| {
+            let mut c = Consumer::starting_at(&q, start);
+            assert_eq!(c.count(), expected);
+            if expected < 40 {
+                c.try_consume(&mut m).unwrap();
+                assert_eq!(m, expected);
+            } else {
+                assert_eq!(c.try_consume(&mut m), Err(ReadError::Empty));
+            }
+        };
+        check(Start::Oldest, 24);
+        check(Start::Latest, 40);
+        check(Start::Sequence(30), 30);
+        check(Start::Sequence(3), 24);
+        check(Start::Sequence(41), 41);
+        check(Start::Back(4), 36);
+        check(Start::Back(100), 24);
+
+        let q = Queue::<usize>::new(16, QueueType::SPSC).unwrap();
+        let mut p = Producer::from(&q);
+        for i in 0..10 {
+            p.produce(&i);
+        }
+        let mut c = Consumer::from(&q);
+        for _ in 0..3 {
+            c.try_consume(&mut m).unwrap();
+        }
+        assert_eq!(Consumer::starting_at(&q, Start::Oldest).count(), 3);
+        assert_eq!(Consumer::starting_at(&q, Start::Back(100)).count(), 3);
+        assert_eq!(Consumer::starting_at(&q, Start::Sequence(100)).count(), 10);
+        assert_eq!(q.read_count(), 10);
+        for i in 10..26 {
+            assert_eq!(p.try_produce(&i), Ok(i));
+        }
+        assert_eq!(p.try_produce(&26), Err(WriteError::Full));
+    }
+
+    #[test]
+    fn sequence_numbers() {
+        let q = Queue::<usize>::new(16, QueueType::MPMC).unwrap();
+        let mut p1 = Producer::from(&q);
+        let mut p2 = Producer::from(&q);
+        let mut c = Consumer::from(&q);
+        let mut m = 0;
+        for i in 0..10 {
+            let seq = if i % 2 == 0 { p1.produce(&i) } else { p2.produce(&i) };
+            assert_eq!(seq, i);
+            assert_eq!(c.try_consume(&mut m), Ok(i));
+        }
+        assert_eq!(p1.produce_batch(&[10, 11, 12]), 10);
+        assert_eq!(c.consume(&mut m), 10);
+        assert_eq!(c.try_consume_with(|m| *m), Ok(11));
+        assert_eq!(c.count() - 1, 11);
+        // Lost messages show up as a gap in the sequence numbers
+        for i in 13..40 {
+            p2.produce(&i);
+        }
+        assert_eq!(c.try_consume(&mut m), Err(ReadError::SpedPast));
+        c.recover_after_error();
+        let seq = p1.produce(&40);
+        assert_eq!(c.try_consume(&mut m), Ok(seq));
+        assert_eq!((seq, m), (40, 40));
+    }
+
+    #[test]
+    fn timestamps() {
+        for clock in [Clock::Monotonic, Clock::Tsc] {
+            let q = Queue::<[u8; 56]>::new_with_clock(16, QueueType::SPMC, clock).unwrap();
+            assert_eq!(q.header.clock(), clock);
+            let mut p = Producer::from(&q);
+            let mut c = Consumer::from(&q);
+            let mut m = [0; 56];
+            let before = clock.now();
+            p.produce(&[1; 56]);
+            p.produce_with(|m| {
+                 m.write([2; 56]);
+             });
+            let (seq, first) = c.try_consume_stamped(&mut m).unwrap();
+            assert_eq!((seq, m[0]), (0, 1));
+            let (seq, second) = c.consume_stamped(&mut m);
+            assert_eq!((seq, m[55]), (1, 2));
+            assert!(before <= first && first <= second && second <= clock.now());
+            assert_eq!(c.try_consume_stamped(&mut m), Err(ReadError::Empty));
+        }
+
+        let q = Queue::<[u8; 56]>::new(16, QueueType::SPMC).unwrap();
+        let mut c = Consumer::from(&q);
+        Producer::from(&q).produce(&[1; 56]);
+        assert_eq!(c.try_consume_stamped(&mut [0; 56]), Ok((0, 0)));
+    }
 }
@@ -0,0 +1,346 @@
+//! Capturing the traffic of a queue to a file, and replaying it into another queue.
+//!
+//! A capture starts with [`CAPTURE_MAGIC`] and the [`Fingerprint`] of the element type, followed
+//! by one record per message: its sequence number and timestamp as little endian u64s, then the
+//! raw bytes of the message. Timestamps are CLOCK_MONOTONIC nanoseconds, taken by the producer
+//! if the queue was created with [`Clock::Monotonic`] and by the recorder otherwise.
+//!
+//! Messages are written out and read back as raw bytes, so only [`Pod`] types can be captured.
+use std::{
+    fs::File,
+    io::{self, BufReader, BufWriter, Read, Write},
+    marker::PhantomData,
+    mem::{size_of, MaybeUninit},
+    path::Path,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    clock::Clock,
+    fingerprint::{Fingerprint, TypeHash},
+    queue::{Consumer, Producer},
+    ReadError,
+};
+
+pub const CAPTURE_MAGIC: [u8; 8] = *b"MAQCAP03";
+const HEADER_SIZE: usize = 8 + 24;
+
+/// Plain old data, message types that can be captured and read back from raw bytes.
+///
+/// # Safety
+/// Every bit pattern has to be a valid value of the type, so no bools, enums or references,
+/// and it can't have padding bytes since those are uninitialized.
+pub unsafe trait Pod: Copy + TypeHash {}
+
+macro_rules! impl_pod {
+    ($($t:ty),*) => { $(unsafe impl Pod for $t {})* };
+}
+impl_pod!(u8, u16, u32, u64, u128, usize, i8, i16, i32, i64, i128, isize, f32, f64);
+unsafe impl<T: Pod, const N: usize> Pod for [T; N] {}
+
+fn as_bytes<T>(v: &T) -> &[u8] {
+    unsafe { std::slice::from_raw_parts(v as *const T as *const u8, size_of::<T>()) }
+}
+
+fn invalid(e: impl Into<Box<dyn std::error::Error + Send + Sync>>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e)
+}
+
+pub(crate) fn write_header<T: TypeHash, W: Write>(out: &mut W) -> io::Result<()> {
+    let f = Fingerprint::of::<T>();
+    out.write_all(&CAPTURE_MAGIC)?;
+    for v in [f.magic, f.layout_version, f.size, f.align] {
+        out.write_all(&v.to_le_bytes())?;
+    }
+    out.write_all(&f.type_hash.to_le_bytes())
+}
+
+pub(crate) fn write_record<T: Pod, W: Write>(out: &mut W, seq: usize, timestamp: u64, msg: &T) -> io::Result<()> {
+    out.write_all(&(seq as u64).to_le_bytes())?;
+    out.write_all(&timestamp.to_le_bytes())?;
+    out.write_all(as_bytes(msg))
+}
+
+// The timestamp of a message just read by consumer, see the module docs
+pub(crate) fn timestamp_of<T: Copy>(consumer: &Consumer<'_, T>, stamp: u64) -> u64 {
+    if consumer.queue.header.clock() == Clock::Monotonic {
+        stamp
+    } else {
+        Clock::Monotonic.now()
+    }
+}
+
+/// Writes every message its consumer reads to a capture.
+/// Messages lost by getting sped past show up as gaps in the sequence numbers, where the
+/// recording continues depends on the [`RecoveryPolicy`](crate::RecoveryPolicy) of the consumer.
+pub struct Recorder<'a, T, W: Write = BufWriter<File>> {
+    consumer: Consumer<'a, T>,
+    out:      W,
+    recorded: usize,
+}
+
+impl<'a, T: Pod> Recorder<'a, T> {
+    pub fn create<P: AsRef<Path>>(consumer: Consumer<'a, T>, path: P) -> io::Result<Self> {
+        Self::new(consumer, BufWriter::new(File::create(path)?))
+    }
+}
+
+impl<'a, T: Pod, W: Write> Recorder<'a, T, W> {
+    /// Writes the capture header to out right away
+    pub fn new(consumer: Consumer<'a, T>, mut out: W) -> io::Result<Self> {
+        write_header::<T, _>(&mut out)?;
+        Ok(Self { consumer, out, recorded: 0 })
+    }
+
+    /// Records all messages that are available right now, returning how many
+    pub fn record_available(&mut self) -> io::Result<usize> {
+        // Overwritten by every consume, like the buffer of a consumer. All zeros is a valid Pod
+        let mut m: T = unsafe { std::mem::zeroed() };
+        let mut n = 0;
+        loop {
+            match self.consumer.try_consume_stamped(&mut m) {
+                Ok((seq, stamp)) => {
+                    write_record(&mut self.out, seq, timestamp_of(&self.consumer, stamp), &m)?;
+                    n += 1;
+                }
+                Err(ReadError::SpedPast) => {
+                    self.consumer.recover_after_error();
+                }
+                Err(_) => break,
+            }
+        }
+        self.recorded += n;
+        Ok(n)
+    }
+
+    /// Records until deadline, checking for new messages every interval
+    pub fn record_until(&mut self, deadline: Instant, interval: Duration) -> io::Result<usize> {
+        let mut n = 0;
+        while Instant::now() < deadline {
+            n += self.record_available()?;
+            std::thread::sleep(interval);
+        }
+        Ok(n + self.record_available()?)
+    }
+
+    /// Total number of messages recorded
+    pub fn recorded(&self) -> usize {
+        self.recorded
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+
+    /// Flushes and hands back the writer
+    pub fn finish(mut self) -> io::Result<W> {
+        self.out.flush()?;
+        Ok(self.out)
+    }
+}
+
+/// A message read back from a capture
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Recorded<T> {
+    pub seq:       u64,
+    /// CLOCK_MONOTONIC nanoseconds at the time it was produced or recorded
+    pub timestamp: u64,
+    pub msg:       T,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ReplaySpeed {
+    /// With the inter-arrival times of the capture
+    Original,
+    AsFastAsPossible,
+    /// Inter-arrival times divided by the factor, i.e. 2.0 replays twice as fast
+    Multiplier(f64),
+}
+
+/// Reads a capture back, either as an iterator over the [`Recorded`] messages or by producing
+/// them into a queue with [`Replayer::replay`].
+pub struct Replayer<T, R: Read = BufReader<File>> {
+    input: R,
+    _t:    PhantomData<T>,
+}
+
+impl<T: Pod> Replayer<T> {
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        Self::new(BufReader::new(File::open(path)?))
+    }
+}
+
+impl<T: Pod, R: Read> Replayer<T, R> {
+    /// Checks that the capture holds messages of type T, see [`TypeHash`], and was written with
+    /// the current layout version of this crate.
+    pub fn new(mut input: R) -> io::Result<Self> {
+        let mut header = [0u8; HEADER_SIZE];
+        input.read_exact(&mut header)?;
+        if header[..8] != CAPTURE_MAGIC {
+            return Err(invalid("not a queue capture"));
+        }
+        let u32_at = |i: usize| u32::from_le_bytes(header[i..i + 4].try_into().unwrap());
+        let found = Fingerprint { magic:          u32_at(8),
+                                  layout_version: u32_at(12),
+                                  size:           u32_at(16),
+                                  align:          u32_at(20),
+                                  type_hash:      u64::from_le_bytes(header[24..32].try_into().unwrap()), };
+        found.check_type::<T>().map_err(invalid)?;
+        Ok(Self { input, _t: PhantomData })
+    }
+
+    /// The next message, None at the end of the capture
+    pub fn next_message(&mut self) -> io::Result<Option<Recorded<T>>> {
+        let mut head = [0u8; 16];
+        // A capture cut off mid record, e.g. by a crashed recorder, ends at the last complete one
+        match self.input.read_exact(&mut head) {
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+            r => r?,
+        }
+        // Any bytes make a valid Pod, once they are all filled in
+        let mut msg = MaybeUninit::<T>::zeroed();
+        let bytes = unsafe { std::slice::from_raw_parts_mut(msg.as_mut_ptr() as *mut u8, size_of::<T>()) };
+        match self.input.read_exact(bytes) {
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+            r => r?,
+        }
+        Ok(Some(Recorded { seq:       u64::from_le_bytes(head[..8].try_into().unwrap()),
+                           timestamp: u64::from_le_bytes(head[8..].try_into().unwrap()),
+                           msg:       unsafe { msg.assume_init() }, }))
+    }
+
+    /// Produces the rest of the capture into producer's queue, returning how many messages.
+    /// The sequence numbers in the new queue start from its own count, gaps aren't reproduced.
+    /// Multipliers that aren't finite and positive are rejected with [`io::ErrorKind::InvalidInput`].
+    pub fn replay(&mut self, producer: &mut Producer<'_, T>, speed: ReplaySpeed) -> io::Result<usize> {
+        let factor = match speed {
+            ReplaySpeed::Original => 1.0,
+            ReplaySpeed::AsFastAsPossible => f64::INFINITY,
+            ReplaySpeed::Multiplier(f) if f.is_finite() && f > 0.0 => f,
+            ReplaySpeed::Multiplier(f) => {
+                let msg = format!("replay speed multiplier has to be finite and positive, got {f}");
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, msg));
+            }
+        };
+        let mut start = None;
+        let mut n = 0;
+        while let Some(r) = self.next_message()? {
+            let (t0, first) = *start.get_or_insert((Instant::now(), r.timestamp));
+            if factor.is_finite() {
+                let offset = r.timestamp.saturating_sub(first) as f64 / factor;
+                wait_until(t0 + Duration::from_nanos(offset as u64));
+            }
+            producer.produce(&r.msg);
+            n += 1;
+        }
+        Ok(n)
+    }
+}
+
+impl<T: Pod, R: Read> Iterator for Replayer<T, R> {
+    type Item = io::Result<Recorded<T>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_message().transpose()
+    }
+}
+
+// Sleeps most of the way, spins the rest to not overshoot by the sleep granularity
+fn wait_until(t: Instant) {
+    const SPIN: Duration = Duration::from_micros(100);
+    loop {
+        let now = Instant::now();
+        if now >= t {
+            return;
+        }
+        let left = t - now;
+        if left > SPIN {
+            std::thread::sleep(left - SPIN);
+        } else {
+            std::hint::spin_loop();
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{Queue, QueueType, RecoveryPolicy};
+
+    #[test]
+    fn record_replay() {
+        let q = Queue::<[u64; 3]>::new(8, QueueType::SPMC).unwrap();
+        let mut p = Producer::from(&q);
+        let consumer = Consumer::from(&q).with_recovery(RecoveryPolicy::Oldest);
+        let mut rec = Recorder::new(consumer, Vec::new()).unwrap();
+        for i in 0..4 {
+            p.produce(&[i; 3]);
+        }
+        assert_eq!(rec.record_available().unwrap(), 4);
+        // Sped past, 12..20 survive
+        for i in 4..20 {
+            p.produce(&[i; 3]);
+        }
+        assert_eq!(rec.record_available().unwrap(), 8);
+        let capture = rec.finish().unwrap();
+        assert_eq!(capture.len(), HEADER_SIZE + 12 * (16 + 24));
+
+        let recorded = Replayer::<[u64; 3], _>::new(&capture[..]).unwrap().collect::<io::Result<Vec<_>>>().unwrap();
+        let seqs = recorded.iter().map(|r| r.seq).collect::<Vec<_>>();
+        assert_eq!(seqs, (0..4).chain(12..20).collect::<Vec<_>>());
+        assert!(recorded.iter().all(|r| r.msg == [r.seq; 3]));
+        assert!(recorded.windows(2).all(|w| w[0].timestamp <= w[1].timestamp));
+
+        let fresh = Queue::<[u64; 3]>::new(16, QueueType::SPMC).unwrap();
+        let mut c = Consumer::from(&fresh);
+        let n = Replayer::<[u64; 3], _>::new(&capture[..]).unwrap()
+                                                         .replay(&mut Producer::from(&fresh), ReplaySpeed::AsFastAsPossible)
+                                                         .unwrap();
+        assert_eq!(n, 12);
+        let mut m = [0; 3];
+        for r in recorded {
+            c.try_consume(&mut m).unwrap();
+            assert_eq!(m, r.msg);
+        }
+
+        // Cut off mid record
+        let mut replayer = Replayer::<[u64; 3], _>::new(&capture[..capture.len() - 3]).unwrap();
+        assert_eq!(replayer.by_ref().count(), 11);
+        assert!(matches!(Replayer::<u64, _>::new(&capture[..]), Err(e) if e.kind() == io::ErrorKind::InvalidData));
+        assert!(matches!(Replayer::<[i64; 3], _>::new(&capture[..]), Err(e) if e.kind() == io::ErrorKind::InvalidData));
+        let mut old = capture.clone();
+        old[12..16].copy_from_slice(&0u32.to_le_bytes());
+        assert!(matches!(Replayer::<[u64; 3], _>::new(&old[..]), Err(e) if e.kind() == io::ErrorKind::InvalidData));
+    }
+
+    #[test]
+    fn replay_timing() {
+        let q = Queue::<u64>::new_with_clock(8, QueueType::SPMC, Clock::Monotonic).unwrap();
+        let mut p = Producer::from(&q);
+        let mut rec = Recorder::new(Consumer::from(&q), Vec::new()).unwrap();
+        for i in 0..3 {
+            p.produce(&i);
+            std::thread::sleep(Duration::from_millis(20));
+        }
+        rec.record_available().unwrap();
+        let capture = rec.finish().unwrap();
+
+        let fresh = Queue::<u64>::new(8, QueueType::SPMC).unwrap();
+        let t = Instant::now();
+        Replayer::<u64, _>::new(&capture[..]).unwrap()
+                                             .replay(&mut Producer::from(&fresh), ReplaySpeed::Original)
+                                             .unwrap();
+        assert!(t.elapsed() >= Duration::from_millis(40));
+        let t = Instant::now();
+        Replayer::<u64, _>::new(&capture[..]).unwrap()
+                                             .replay(&mut Producer::from(&fresh), ReplaySpeed::Multiplier(4.0))
+                                             .unwrap();
+        // Only a lower bound, the replay can be held up arbitrarily on a busy machine
+        assert!(t.elapsed() >= Duration::from_millis(10));
+        for f in [0.0, -1.0, f64::NAN, f64::INFINITY] {
+            let r = Replayer::<u64, _>::new(&capture[..]).unwrap()
+                                                         .replay(&mut Producer::from(&fresh), ReplaySpeed::Multiplier(f));
+            assert!(matches!(r, Err(e) if e.kind() == io::ErrorKind::InvalidInput));
+        }
+    }
+}
@@ -1,9 +1,11 @@
 use std::arch::asm;
 use std::cell::UnsafeCell;
 use std::fmt;
+use std::mem::MaybeUninit;
 use std::sync::atomic::{compiler_fence, fence, AtomicUsize, Ordering};
+use std::time::Instant;
 
-use super::ReadError;
+use super::{wait::pause, ReadError, Timeout};
 //TODO: Make the types more rust like. I.e. on copy types -> copy on write/read, clone types -> copy std::mem::forget till read etc
 /// A sequential lock
 #[repr(C, align(64))]
@@ -51,6 +53,29 @@ impl<T: Copy> Seqlock<T> {
         }
     }
 
+    /// Runs f on the data in place and only returns its result if the version didn't change meanwhile.
+    /// f may see a torn value, it should compute its result and not act on the data otherwise.
+    #[inline(always)]
+    pub fn read_with<R, F>(&self, f: F, expected_version: usize) -> Result<R, ReadError>
+    where
+        F: FnOnce(&T) -> R,
+    {
+        let v1 = self.version.load(Ordering::Acquire);
+        if v1 < expected_version {
+            return Err(ReadError::Empty);
+        }
+
+        compiler_fence(Ordering::AcqRel);
+        let r = f(unsafe { &*self.data.get() });
+        compiler_fence(Ordering::AcqRel);
+        let v2 = self.version.load(Ordering::Acquire);
+        if v2 == expected_version {
+            Ok(r)
+        } else {
+            Err(ReadError::SpedPast)
+        }
+    }
+
     #[inline(never)]
     pub fn read_no_ver(&self, result: &mut T) {
         loop {
@@ -64,8 +89,47 @@ impl<T: Copy> Seqlock<T> {
             if v1 == v2 && v1 & 1 == 0 {
                 return;
             }
-            #[cfg(target_arch="x86_64")]
-            unsafe {std::arch::x86_64::_mm_pause()};
+            pause();
+        }
+    }
+
+    /// [`Seqlock::read_no_ver`] that gives up at deadline, e.g. when a crashed writer left the version odd
+    #[inline(never)]
+    pub fn read_no_ver_until(&self, result: &mut T, deadline: Instant) -> Result<(), Timeout> {
+        loop {
+            let v1 = self.version.load(Ordering::Acquire);
+            compiler_fence(Ordering::AcqRel);
+            unsafe {
+                *result = *self.data.get();
+            }
+            compiler_fence(Ordering::AcqRel);
+            let v2 = self.version.load(Ordering::Acquire);
+            if v1 == v2 && v1 & 1 == 0 {
+                return Ok(());
+            }
+            if Instant::now() >= deadline {
+                return Err(Timeout);
+            }
+            pause();
+        }
+    }
+
+    /// [`Seqlock::read_with`] without a version to check against, retries f until it ran on a consistent value.
+    #[inline(always)]
+    pub fn read_no_ver_with<R, F>(&self, mut f: F) -> R
+    where
+        F: FnMut(&T) -> R,
+    {
+        loop {
+            let v1 = self.version.load(Ordering::Acquire);
+            compiler_fence(Ordering::AcqRel);
+            let r = f(unsafe { &*self.data.get() });
+            compiler_fence(Ordering::AcqRel);
+            let v2 = self.version.load(Ordering::Acquire);
+            if v1 == v2 && v1 & 1 == 0 {
+                return r;
+            }
+            pause();
         }
     }
 
@@ -94,6 +158,19 @@ impl<T: Copy> Seqlock<T> {
         });
     }
 
+    /// Lets f write directly into the data while the version is odd, avoiding the copy of [`Seqlock::write`].
+    /// The data still holds the previous value, f is expected to overwrite all of it.
+    ///
+    /// f must not panic: the version would stay odd, and readers treat the lock as being written
+    /// to forever, the same as when the writing process dies mid write.
+    #[inline(always)]
+    pub fn write_with<F>(&self, f: F)
+    where
+        F: FnOnce(&mut MaybeUninit<T>),
+    {
+        self._write(|| f(unsafe { &mut *(self.data.get() as *mut MaybeUninit<T>) }));
+    }
+
     #[inline(always)]
     fn _write_unpoison<F>(&self, f: F)
     where
@@ -118,6 +195,16 @@ impl<T: Copy> Seqlock<T> {
         });
     }
 
+    /// [`Seqlock::write_unpoison`] that lets f write directly into the data, see [`Seqlock::write_with`].
+    /// f must not panic.
+    #[inline(always)]
+    pub fn write_unpoison_with<F>(&self, f: F)
+    where
+        F: FnOnce(&mut MaybeUninit<T>),
+    {
+        self._write_unpoison(|| f(unsafe { &mut *(self.data.get() as *mut MaybeUninit<T>) }));
+    }
+
     #[inline(always)]
     #[allow(named_asm_labels)]
     fn _write_multi<F>(&self, f: F)
@@ -143,6 +230,16 @@ impl<T: Copy> Seqlock<T> {
             unsafe { self.data.get().copy_from(val as *const T, 1) };
         });
     }
+
+    /// [`Seqlock::write_multi`] that lets f update the data in place.
+    /// f must not panic, see [`Seqlock::write_with`].
+    #[inline(always)]
+    pub fn write_multi_with<F>(&self, f: F)
+    where
+        F: FnOnce(&mut T),
+    {
+        self._write_multi(|| f(unsafe { &mut *self.data.get() }));
+    }
 }
 
 impl<T: Copy + Default> Default for Seqlock<T> {
@@ -270,6 +367,41 @@ mod tests {
         read_test_multi::<65536>()
     }
 
+    #[test]
+    fn write_with() {
+        let lock = Seqlock::new([0usize; 4]);
+        lock.write_with(|data| {
+            data.write([1; 4]);
+        });
+        let mut m = [0; 4];
+        assert_eq!(lock.read(&mut m, 2), Ok(()));
+        assert_eq!(m, [1; 4]);
+    }
+
+    #[test]
+    fn read_with() {
+        let lock = Seqlock::new([1usize; 4]);
+        lock.set_version(2);
+        assert_eq!(lock.read_with(|m| m[0], 2), Ok(1));
+        assert_eq!(lock.read_with(|m| m[0], 4), Err(ReadError::Empty));
+        assert_eq!(lock.read_no_ver_with(|m| m[3]), 1);
+        // Mid write
+        lock.set_version(3);
+        assert_eq!(lock.read_with(|m| m[0], 2), Err(ReadError::SpedPast));
+    }
+
+    #[test]
+    fn read_no_ver_until() {
+        let lock = Seqlock::new(1usize);
+        let mut m = 0;
+        assert_eq!(lock.read_no_ver_until(&mut m, Instant::now()), Ok(()));
+        assert_eq!(m, 1);
+        lock.set_version(3);
+        let deadline = Instant::now() + std::time::Duration::from_millis(5);
+        assert_eq!(lock.read_no_ver_until(&mut m, deadline), Err(Timeout));
+        assert!(Instant::now() >= deadline);
+    }
+
     #[test]
     fn write_unpoison() {
         let lock = Seqlock::default();
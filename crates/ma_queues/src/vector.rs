@@ -1,12 +1,25 @@
-use std::{alloc::Layout, mem::MaybeUninit, path::Path};
-
-use crate::seqlock::*;
+use std::{
+    alloc::Layout,
+    mem::MaybeUninit,
+    path::Path,
+    sync::atomic::{AtomicBool, Ordering},
+    time::{Duration, Instant},
+};
+
+use crate::{
+    fingerprint::{Fingerprint, TypeHash},
+    handle::{Handle, VectorHandle},
+    seqlock::*,
+    QueueError, Timeout,
+};
 
 #[derive(Debug)]
 #[repr(C)]
 pub struct VectorHeader {
-    elsize:  usize,
-    bufsize: usize,
+    fingerprint: Fingerprint,
+    elsize:      usize,
+    bufsize:     usize,
+    removed:     AtomicBool,
 }
 
 #[repr(C, align(64))]
@@ -14,14 +27,17 @@ pub struct SeqlockVector<T> {
     header: VectorHeader,
     buffer: [Seqlock<T>],
 }
-impl<T: Copy> SeqlockVector<T> {
-    pub fn new(len: usize) -> &'static Self {
+impl<T: Copy + TypeHash> SeqlockVector<T> {
+    /// Allocs (unshared) memory and initializes a new vector from it, the memory is freed when
+    /// the handle is dropped
+    pub fn new(len: usize) -> VectorHandle<T> {
         // because we don't need len to be power of 2
         let size = std::mem::size_of::<VectorHeader>() + len * std::mem::size_of::<Seqlock<T>>();
+        let layout = Layout::array::<u8>(size).unwrap().align_to(64).unwrap().pad_to_align();
 
         unsafe {
-            let ptr = std::alloc::alloc_zeroed(Layout::array::<u8>(size).unwrap().align_to(64).unwrap().pad_to_align());
-            Self::from_uninitialized_ptr(ptr, len)
+            let ptr = std::alloc::alloc_zeroed(layout);
+            Handle::heap(Self::from_uninitialized_ptr(ptr, len), ptr, layout)
         }
     }
 
@@ -29,26 +45,36 @@ impl<T: Copy> SeqlockVector<T> {
         std::mem::size_of::<VectorHeader>() + len * std::mem::size_of::<Seqlock<T>>()
     }
 
-    pub fn from_uninitialized_ptr(ptr: *mut u8, len: usize) -> &'static Self {
+    /// Initializes a new vector in the memory at ptr
+    ///
+    /// # Safety
+    /// ptr has to point to at least [`SeqlockVector::size_of`] bytes, 64 byte aligned, that stay valid
+    /// for as long as the vector is used.
+    pub unsafe fn from_uninitialized_ptr(ptr: *mut u8, len: usize) -> &'static Self {
         unsafe {
             // why len? because the size in the fat pointer ONLY cares about the unsized part of the
             // struct i.e. the length of the buffer
             let q = &mut *(std::ptr::slice_from_raw_parts_mut(ptr, len) as *mut SeqlockVector<T>);
             let elsize = std::mem::size_of::<Seqlock<T>>();
+            q.header.fingerprint = Fingerprint::of::<T>();
             q.header.bufsize = len;
             q.header.elsize = elsize;
+            q.header.removed = AtomicBool::new(false);
             q
         }
     }
 
     #[allow(dead_code)]
-    fn from_initialized_ptr(ptr: *mut VectorHeader) -> &'static mut Self {
+    fn from_initialized_ptr(ptr: *mut VectorHeader) -> Result<&'static mut Self, QueueError> {
         unsafe {
+            (*ptr).fingerprint.check_type::<T>()?;
             let len = (*ptr).bufsize;
-            &mut *(std::ptr::slice_from_raw_parts_mut(ptr, len) as *mut SeqlockVector<T>)
+            Ok(&mut *(std::ptr::slice_from_raw_parts_mut(ptr, len) as *mut SeqlockVector<T>))
         }
     }
+}
 
+impl<T: Copy> SeqlockVector<T> {
     //TODO: ErrorHandling
     fn load(&self, pos: usize) -> &Seqlock<T> {
         unsafe { self.buffer.get_unchecked(pos) }
@@ -69,6 +95,23 @@ impl<T: Copy> SeqlockVector<T> {
         lock.read_no_ver(result);
     }
 
+    /// [`SeqlockVector::read`] that returns [`Timeout`] instead of spinning forever
+    /// when the element stays locked, e.g. because its writer crashed mid write.
+    pub fn read_until(&self, pos: usize, result: &mut T, deadline: Instant) -> Result<(), Timeout> {
+        let lock = self.load(pos);
+        lock.read_no_ver_until(result, deadline)
+    }
+
+    pub fn read_timeout(&self, pos: usize, result: &mut T, timeout: Duration) -> Result<(), Timeout> {
+        self.read_until(pos, result, Instant::now() + timeout)
+    }
+
+    /// Runs f on the element at pos in place, retrying until it saw a consistent element.
+    pub fn read_with<R, F: FnMut(&T) -> R>(&self, pos: usize, f: F) -> R {
+        let lock = self.load(pos);
+        lock.read_no_ver_with(f)
+    }
+
     pub fn read_copy(&self, pos: usize) -> T {
         let mut out = unsafe { MaybeUninit::uninit().assume_init() };
         let lock = self.load(pos);
@@ -80,36 +123,65 @@ impl<T: Copy> SeqlockVector<T> {
         self.header.bufsize as usize
     }
 
+    /// Whether the shared memory was unlinked
+    pub fn is_removed(&self) -> bool {
+        self.header.removed.load(Ordering::Acquire)
+    }
+
+    fn mark_removed(&self) {
+        self.header.removed.store(true, Ordering::Release);
+    }
+
     pub fn iter(&self) -> VectorIterator<'_, T> {
         VectorIterator { vector: self, next_id: 0 }
     }
 }
 
 #[cfg(feature = "shmem")]
-impl<T: Copy> SeqlockVector<T> {
-    pub fn shared<P: AsRef<Path>>(shmem_flink: P, len: usize) -> Result<&'static Self, &'static str> {
+impl<T: Copy + TypeHash> SeqlockVector<T> {
+    /// Creates a vector in shared memory at shmem_flink, or opens it if it exists already.
+    /// Dropping the handle unmaps the vector but doesn't remove it.
+    pub fn shared<P: AsRef<Path>>(shmem_flink: P, len: usize) -> Result<VectorHandle<T>, QueueError> {
         use shared_memory::{ShmemConf, ShmemError};
         match ShmemConf::new().size(Self::size_of(len)).flink(&shmem_flink).create() {
-            Ok(shmem) => {
-                let ptr = shmem.as_ptr();
-                std::mem::forget(shmem);
-                Ok(Self::from_uninitialized_ptr(ptr, len))
+            Ok(mut shmem) => {
+                shmem.set_owner(false);
+                let v = unsafe { Self::from_uninitialized_ptr(shmem.as_ptr(), len) };
+                Ok(Handle::shmem(v, shmem, Self::mark_removed))
             }
             Err(ShmemError::LinkExists) => {
-                let shmem = ShmemConf::new().flink(shmem_flink).open().unwrap();
-                let ptr = shmem.as_ptr() as *mut VectorHeader;
-                std::mem::forget(shmem);
-                let v = Self::from_initialized_ptr(ptr);
+                let shmem = ShmemConf::new().flink(&shmem_flink).open()?;
+                let v = Self::from_initialized_ptr(shmem.as_ptr() as *mut VectorHeader)?;
                 if v.header.bufsize < len {
-                    Err("Existing shmem too small")
+                    Err(QueueError::TooSmall)
                 } else {
                     v.header.bufsize = len;
-                    Ok(v)
+                    Ok(Handle::shmem(v, shmem, Self::mark_removed))
                 }
             }
-            Err(_) => Err("Unable to create or open shmem flink."),
+            Err(e) => {
+                eprintln!("Unable to create or open shmem flink {:?} : {e}", shmem_flink.as_ref());
+                Err(e.into())
+            }
         }
     }
+
+    /// Removes the shared memory of the vector at shmem_flink. Handles that still have it open
+    /// keep working, and can find out through [`SeqlockVector::is_removed`].
+    /// Works regardless of the element type or layout the vector was created with.
+    pub fn unlink<P: AsRef<Path>>(shmem_flink: P) -> Result<(), QueueError> {
+        use shared_memory::ShmemConf;
+        let mut shmem = ShmemConf::new().flink(&shmem_flink).open()?;
+        // Where removed lives is only known for our current layout
+        if shmem.len() >= std::mem::size_of::<VectorHeader>() {
+            let header = unsafe { &*(shmem.as_ptr() as *const VectorHeader) };
+            if header.fingerprint.check_layout().is_ok() {
+                header.removed.store(true, Ordering::Release);
+            }
+        }
+        shmem.set_owner(true);
+        Ok(())
+    }
 }
 impl<T: Clone + std::fmt::Debug> std::fmt::Debug for SeqlockVector<T> {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
@@ -135,3 +207,36 @@ impl<'a, T: Copy + Clone> Iterator for VectorIterator<'a, T> {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn read_timeout() {
+        let v = SeqlockVector::<usize>::new(4);
+        v.write(1, &3);
+        let mut m = 0;
+        v.read_timeout(1, &mut m, Duration::from_millis(1)).unwrap();
+        assert_eq!(m, 3);
+        // Writer dies mid write, leaving the version odd
+        let _ = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| v.load(1).write_with(|_| panic!("crashed"))));
+        assert_eq!(v.read_timeout(1, &mut m, Duration::from_millis(1)), Err(Timeout));
+    }
+
+    #[test]
+    #[cfg(feature = "shmem")]
+    fn fingerprint_mismatch_shared() {
+        let path = std::path::Path::new("/dev/shm/vector_fingerprint_test");
+        let _ = std::fs::remove_file(path);
+        let v = SeqlockVector::<usize>::shared(path, 8).unwrap();
+        v.write(3, &42);
+        assert_eq!(SeqlockVector::<usize>::shared(path, 8).unwrap().read_copy(3), 42);
+        assert!(matches!(SeqlockVector::<[u8; 56]>::shared(path, 8), Err(QueueError::FingerprintMismatch { .. })));
+        assert!(matches!(SeqlockVector::<u64>::shared(path, 8), Err(QueueError::FingerprintMismatch { .. })));
+        assert!(matches!(SeqlockVector::<usize>::shared(path, 16), Err(QueueError::TooSmall)));
+        SeqlockVector::<[u8; 56]>::unlink(path).unwrap();
+        assert!(v.is_removed());
+        assert!(!path.exists());
+    }
+}
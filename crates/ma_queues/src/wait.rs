@@ -0,0 +1,256 @@
+//! What a blocking consume does while the queue is empty.
+//!
+//! [`BusySpin`] gives the lowest latency but burns a core per idle consumer, the others trade
+//! latency for cpu. [`Park`] puts the consumer to sleep on a futex in the queue header until a
+//! producer wakes it up, which works across processes for shared queues.
+use std::{
+    sync::atomic::{AtomicU32, Ordering},
+    time::Duration,
+};
+
+/// Lives in the queue header, lets producers wake up [`Park`]ed consumers.
+/// Producers only pay for a relaxed load of waiters as long as nobody parks.
+#[derive(Debug, Default)]
+#[repr(C)]
+pub struct Notifier {
+    waiters: AtomicU32, // 4
+    seq:     AtomicU32, // 8 the futex word, bumped on every wake up
+}
+
+impl Notifier {
+    pub(crate) fn notify(&self) {
+        if self.waiters.load(Ordering::Relaxed) != 0 {
+            self.seq.fetch_add(1, Ordering::Release);
+            futex_wake(&self.seq);
+        }
+    }
+
+    pub fn waiters(&self) -> u32 {
+        self.waiters.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn register(&self) -> u32 {
+        self.waiters.fetch_add(1, Ordering::SeqCst);
+        self.seq.load(Ordering::SeqCst)
+    }
+
+    pub(crate) fn unregister(&self) {
+        self.waiters.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn seq(&self) -> u32 {
+        self.seq.load(Ordering::Acquire)
+    }
+
+    // Sleeps until woken up if seq is still the current one, or until timeout
+    pub(crate) fn wait(&self, seq: u32, timeout: Duration) {
+        futex_wait(&self.seq, seq, timeout)
+    }
+
+    // Wakes up all waiters, also when no message was produced
+    pub(crate) fn wake_all(&self) {
+        self.seq.fetch_add(1, Ordering::Release);
+        futex_wake(&self.seq);
+    }
+}
+
+pub trait WaitStrategy {
+    /// Called every time a blocking consume finds the queue empty
+    fn wait(&mut self, notifier: &Notifier);
+    /// Called after a blocking consume got a message
+    fn reset(&mut self, _notifier: &Notifier) {}
+}
+
+#[inline(always)]
+pub(crate) fn pause() {
+    std::hint::spin_loop()
+}
+
+/// Spins on the queue, the default
+#[derive(Debug, Clone, Copy, Default)]
+pub struct BusySpin;
+
+impl WaitStrategy for BusySpin {
+    #[inline(always)]
+    fn wait(&mut self, _notifier: &Notifier) {
+        pause()
+    }
+}
+
+/// Spins for a number of tries, then yields to the scheduler on every following one
+#[derive(Debug, Clone, Copy)]
+pub struct SpinYield {
+    spins: u32,
+    tries: u32,
+}
+
+impl SpinYield {
+    pub fn new(spins: u32) -> Self {
+        Self { spins, tries: 0 }
+    }
+}
+
+impl Default for SpinYield {
+    fn default() -> Self {
+        Self::new(1000)
+    }
+}
+
+impl WaitStrategy for SpinYield {
+    fn wait(&mut self, _notifier: &Notifier) {
+        if self.tries < self.spins {
+            self.tries += 1;
+            pause()
+        } else {
+            std::thread::yield_now()
+        }
+    }
+
+    fn reset(&mut self, _notifier: &Notifier) {
+        self.tries = 0;
+    }
+}
+
+/// Spins for a number of tries, then sleeps, doubling the sleep from min up to max
+#[derive(Debug, Clone, Copy)]
+pub struct SpinSleep {
+    spins: u32,
+    tries: u32,
+    min:   Duration,
+    max:   Duration,
+    sleep: Duration,
+}
+
+impl SpinSleep {
+    pub fn new(spins: u32, min: Duration, max: Duration) -> Self {
+        Self { spins, tries: 0, min, max, sleep: min }
+    }
+}
+
+impl Default for SpinSleep {
+    fn default() -> Self {
+        Self::new(1000, Duration::from_micros(10), Duration::from_millis(1))
+    }
+}
+
+impl WaitStrategy for SpinSleep {
+    fn wait(&mut self, _notifier: &Notifier) {
+        if self.tries < self.spins {
+            self.tries += 1;
+            pause()
+        } else {
+            std::thread::sleep(self.sleep);
+            self.sleep = (self.sleep * 2).min(self.max);
+        }
+    }
+
+    fn reset(&mut self, _notifier: &Notifier) {
+        self.tries = 0;
+        self.sleep = self.min;
+    }
+}
+
+/// Spins for a number of tries, then sleeps on the futex in the queue header until a producer
+/// wakes it up.
+///
+/// Producers check for parked consumers without a fence, so in a rare race a wake up can be
+/// missed. The consumer then wakes up by itself after timeout.
+#[derive(Debug, Clone, Copy)]
+pub struct Park {
+    spins:   u32,
+    tries:   u32,
+    timeout: Duration,
+    // The futex word when we registered as waiter
+    seq:     Option<u32>,
+}
+
+impl Park {
+    pub fn new(spins: u32, timeout: Duration) -> Self {
+        Self { spins, tries: 0, timeout, seq: None }
+    }
+}
+
+impl Default for Park {
+    fn default() -> Self {
+        Self::new(1000, Duration::from_millis(10))
+    }
+}
+
+impl WaitStrategy for Park {
+    fn wait(&mut self, notifier: &Notifier) {
+        if self.tries < self.spins {
+            self.tries += 1;
+            return pause();
+        }
+        match self.seq {
+            // The queue is checked once more after registering, so a message produced
+            // in between doesn't leave us sleeping
+            None => self.seq = Some(notifier.register()),
+            Some(seq) => {
+                notifier.wait(seq, self.timeout);
+                self.seq = Some(notifier.seq());
+            }
+        }
+    }
+
+    fn reset(&mut self, notifier: &Notifier) {
+        self.tries = 0;
+        if self.seq.take().is_some() {
+            notifier.unregister();
+        }
+    }
+}
+
+// Not private futexes, the word may be shared between processes
+#[cfg(target_os = "linux")]
+fn futex_wait(word: &AtomicU32, expected: u32, timeout: Duration) {
+    let ts = libc::timespec { tv_sec:  timeout.as_secs() as libc::time_t,
+                              tv_nsec: timeout.subsec_nanos() as libc::c_long, };
+    unsafe {
+        libc::syscall(libc::SYS_futex,
+                      word.as_ptr(),
+                      libc::FUTEX_WAIT,
+                      expected,
+                      &ts as *const libc::timespec)
+    };
+}
+
+#[cfg(target_os = "linux")]
+fn futex_wake(word: &AtomicU32) {
+    unsafe { libc::syscall(libc::SYS_futex, word.as_ptr(), libc::FUTEX_WAKE, i32::MAX) };
+}
+
+#[cfg(not(target_os = "linux"))]
+fn futex_wait(_word: &AtomicU32, _expected: u32, timeout: Duration) {
+    std::thread::sleep(timeout.min(Duration::from_micros(100)));
+}
+
+#[cfg(not(target_os = "linux"))]
+fn futex_wake(_word: &AtomicU32) {}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn park() {
+        let n: Notifier = unsafe { std::mem::zeroed() };
+        let mut p = Park::new(2, Duration::from_secs(10));
+        p.wait(&n);
+        p.wait(&n);
+        assert_eq!(n.waiters(), 0);
+        p.wait(&n);
+        assert_eq!(n.waiters(), 1);
+        std::thread::scope(|s| {
+            s.spawn(|| {
+                 std::thread::sleep(Duration::from_millis(10));
+                 n.notify();
+             });
+            let t = std::time::Instant::now();
+            p.wait(&n);
+            assert!(t.elapsed() < Duration::from_secs(5));
+        });
+        p.reset(&n);
+        assert_eq!(n.waiters(), 0);
+    }
+}